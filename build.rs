@@ -0,0 +1,14 @@
+//! Captures the compiler version used for the `process.runtime.*` resource attributes.
+//!
+//! Only the rustc version is emitted, the git metadata of whatever repository contains the
+//! dependency checkout is irrelevant to this crate.
+
+#[path = "src/build.rs"]
+#[allow(dead_code)]
+mod build;
+
+fn main() {
+    build::emit_rustc_version();
+    println!("cargo:rerun-if-changed=build.rs");
+    println!("cargo:rerun-if-changed=src/build.rs");
+}
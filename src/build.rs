@@ -0,0 +1,124 @@
+//! Helpers for build scripts that embed build metadata for [`crate::build_info!`].
+//!
+//! ```ignore
+//! // build.rs
+//! fn main() {
+//!     telemetry::build::emit();
+//! }
+//! ```
+//!
+//! The module only depends on `std` so that it can be included by this crate's own build script.
+
+use std::{
+    env,
+    path::Path,
+    process::Command,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+/// Emits `TELEMETRY_*` compile time variables for the crate being built.
+///
+/// The git sha is taken from `git rev-parse HEAD`, falling back to the CI variables read by
+/// [`crate::build_info!`], and the timestamp honors `SOURCE_DATE_EPOCH` for reproducible builds.
+pub fn emit() {
+    if let Some(sha) = command("git", &["rev-parse", "HEAD"]) {
+        println!("cargo:rustc-env=TELEMETRY_GIT_SHA={sha}");
+        rerun_if_head_changed();
+    }
+
+    emit_rustc_version();
+
+    let timestamp = env::var("SOURCE_DATE_EPOCH")
+        .ok()
+        .and_then(|epoch| epoch.parse().ok())
+        .unwrap_or_else(|| {
+            SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map(|duration| duration.as_secs())
+                .unwrap_or_default()
+        });
+    println!(
+        "cargo:rustc-env=TELEMETRY_BUILD_TIMESTAMP={}",
+        rfc3339(timestamp)
+    );
+
+    if let Ok(profile) = env::var("PROFILE") {
+        println!("cargo:rustc-env=TELEMETRY_BUILD_PROFILE={profile}");
+    }
+
+    println!("cargo:rerun-if-env-changed=SOURCE_DATE_EPOCH");
+}
+
+/// Emits only `TELEMETRY_RUSTC_VERSION`, the version of the compiler building the crate.
+pub fn emit_rustc_version() {
+    let rustc = env::var("RUSTC").unwrap_or_else(|_| "rustc".to_string());
+    let rustc_version = command(&rustc, &["--version"]).unwrap_or_else(|| "unknown".to_string());
+    println!("cargo:rustc-env=TELEMETRY_RUSTC_VERSION={rustc_version}");
+    println!("cargo:rerun-if-env-changed=RUSTC");
+}
+
+/// Reruns the build script on checkouts and on new commits to the checked out branch.
+///
+/// `HEAD` only changes on checkouts, commits update the branch ref which is either a loose file or
+/// an entry of `packed-refs`. Paths are resolved by git to support worktrees and subdirectories.
+fn rerun_if_head_changed() {
+    let branch = command("git", &["symbolic-ref", "-q", "HEAD"]);
+    let refs = ["HEAD", "packed-refs"].into_iter().chain(branch.as_deref());
+
+    for git_ref in refs {
+        let Some(path) = command("git", &["rev-parse", "--git-path", git_ref]) else {
+            continue;
+        };
+        // Missing files would rerun the script on every build
+        if Path::new(&path).exists() {
+            println!("cargo:rerun-if-changed={path}");
+        }
+    }
+}
+
+fn command(program: &str, args: &[&str]) -> Option<String> {
+    let output = Command::new(program).args(args).output().ok()?;
+    let output = String::from_utf8(output.stdout).ok()?;
+    let output = output.trim();
+    (!output.is_empty()).then(|| output.to_string())
+}
+
+/// Formats seconds since the unix epoch as `YYYY-MM-DDTHH:MM:SSZ`.
+fn rfc3339(timestamp: u64) -> String {
+    let (days, seconds) = (timestamp / 86_400, timestamp % 86_400);
+
+    // Civil date from days since the epoch, see http://howardhinnant.github.io/date_algorithms.html
+    let z = days as i64 + 719_468;
+    let era = z.div_euclid(146_097);
+    let day_of_era = z.rem_euclid(146_097);
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36_524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let month_index = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * month_index + 2) / 5 + 1;
+    let month = if month_index < 10 {
+        month_index + 3
+    } else {
+        month_index - 9
+    };
+    let year = year_of_era + era * 400 + i64::from(month <= 2);
+
+    format!(
+        "{year:04}-{month:02}-{day:02}T{:02}:{:02}:{:02}Z",
+        seconds / 3600,
+        seconds % 3600 / 60,
+        seconds % 60
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_rfc3339() {
+        assert_eq!(rfc3339(0), "1970-01-01T00:00:00Z");
+        assert_eq!(rfc3339(951_782_400), "2000-02-29T00:00:00Z");
+        assert_eq!(rfc3339(1_792_281_599), "2026-10-17T23:59:59Z");
+    }
+}
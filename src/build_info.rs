@@ -0,0 +1,90 @@
+//! Build metadata embedded at compile time, see [`crate::build_info!`].
+
+use opentelemetry::{KeyValue, global};
+
+/// Version of the compiler that built this crate, which is also the one that built the caller.
+#[doc(hidden)]
+pub const RUSTC_VERSION: &str = env!("TELEMETRY_RUSTC_VERSION");
+
+/// Metadata of the calling crate's build, created with [`crate::build_info!`].
+#[derive(Clone, Copy, Debug)]
+pub struct BuildInfo {
+    pub version: &'static str,
+    pub git_sha: &'static str,
+    pub rustc_version: &'static str,
+    pub build_timestamp: &'static str,
+    pub profile: &'static str,
+}
+
+/// Captures the [`BuildInfo`] of the calling crate.
+///
+/// The git sha is read at compile time from `TELEMETRY_GIT_SHA`, which is set by
+/// [`crate::build::emit`] in the caller's build script, or from the commit variables of common CI
+/// systems. Values that cannot be determined are reported as `unknown`.
+#[macro_export]
+macro_rules! build_info {
+    () => {
+        $crate::BuildInfo {
+            version: env!("CARGO_PKG_VERSION"),
+            git_sha: option_env!("TELEMETRY_GIT_SHA")
+                .or(option_env!("GITHUB_SHA"))
+                .or(option_env!("CI_COMMIT_SHA"))
+                .or(option_env!("BUILDKITE_COMMIT"))
+                .or(option_env!("CIRCLE_SHA1"))
+                .or(option_env!("GIT_COMMIT"))
+                .or(option_env!("VERGEN_GIT_SHA"))
+                .unwrap_or("unknown"),
+            rustc_version: match option_env!("TELEMETRY_RUSTC_VERSION") {
+                Some(version) => version,
+                None => $crate::RUSTC_VERSION,
+            },
+            build_timestamp: option_env!("TELEMETRY_BUILD_TIMESTAMP").unwrap_or("unknown"),
+            profile: match option_env!("TELEMETRY_BUILD_PROFILE") {
+                Some(profile) => profile,
+                None if cfg!(debug_assertions) => "debug",
+                None => "release",
+            },
+        }
+    };
+}
+
+impl BuildInfo {
+    pub(crate) fn git_sha(&self) -> Option<&'static str> {
+        (self.git_sha != "unknown").then_some(self.git_sha)
+    }
+
+    /// Registers the `build_info` gauge, which always reports `1` labeled with the build metadata.
+    pub(crate) fn register_gauge(self) {
+        let attributes = [
+            KeyValue::new("version", self.version),
+            KeyValue::new("git_sha", self.git_sha),
+            KeyValue::new("rustc_version", self.rustc_version),
+            KeyValue::new("build_timestamp", self.build_timestamp),
+            KeyValue::new("profile", self.profile),
+        ];
+
+        global::meter("telemetry")
+            .u64_observable_gauge("build_info")
+            .with_callback(move |observer| observer.observe(1, &attributes))
+            .build();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    #[test]
+    fn test_build_info() {
+        let info = crate::build_info!();
+
+        assert_eq!(info.version, env!("CARGO_PKG_VERSION"));
+        assert_eq!(info.rustc_version, env!("TELEMETRY_RUSTC_VERSION"));
+        // This crate's build script only emits the rustc version
+        assert_eq!(info.build_timestamp, "unknown");
+        let profile = if cfg!(debug_assertions) {
+            "debug"
+        } else {
+            "release"
+        };
+        assert_eq!(info.profile, profile);
+    }
+}
@@ -0,0 +1,149 @@
+//! Exporter wrapper that accounts for failed OTLP exports.
+//!
+//! Failed batches are counted in `telemetry_export_failures` (and their items in
+//! `telemetry_export_dropped`) and logged under the [`TARGET`] target. That target is suppressed
+//! from the OTLP pipelines together with the exporter's own dependencies, so a collector outage
+//! does not produce more telemetry to export.
+
+use opentelemetry::{KeyValue, global};
+use opentelemetry_sdk::{
+    Resource,
+    error::OTelSdkResult,
+    logs::{LogBatch, LogExporter},
+    metrics::{Temporality, data::ResourceMetrics, exporter::PushMetricExporter},
+    trace::{SpanData, SpanExporter},
+};
+use std::time::Duration;
+use tracing::warn;
+
+pub(crate) const TARGET: &str = "telemetry::export";
+
+/// Targets whose events are emitted while exporting and must never be exported themselves
+const SUPPRESSED_TARGETS: &[&str] = &[
+    "h2",
+    "hyper",
+    "hyper_util",
+    "tonic",
+    "tower",
+    "opentelemetry",
+    "opentelemetry_sdk",
+    "opentelemetry_otlp",
+    "opentelemetry_http",
+    TARGET,
+];
+
+/// Whether events of `target` are filtered out of the OTLP log and span pipelines.
+pub(crate) fn is_suppressed(target: &str) -> bool {
+    SUPPRESSED_TARGETS.iter().any(|suppressed| {
+        target
+            .strip_prefix(suppressed)
+            .is_some_and(|rest| rest.is_empty() || rest.starts_with("::"))
+    })
+}
+
+#[derive(Debug)]
+pub(crate) struct InstrumentedExporter<E> {
+    inner: E,
+    signal: &'static str,
+}
+
+impl<E> InstrumentedExporter<E> {
+    pub(crate) fn new(inner: E, signal: &'static str) -> Self {
+        Self { inner, signal }
+    }
+
+    fn record(&self, result: OTelSdkResult, items: usize) -> OTelSdkResult {
+        if let Err(error) = &result {
+            // Resolved on every failure since the exporters are built before the meter provider
+            let meter = global::meter("telemetry");
+            let attributes = [KeyValue::new("signal", self.signal)];
+            meter
+                .u64_counter("telemetry_export_failures")
+                .build()
+                .add(1, &attributes);
+            meter
+                .u64_counter("telemetry_export_dropped")
+                .build()
+                .add(items as u64, &attributes);
+
+            warn!(target: TARGET, %error, signal = self.signal, items, "Failed to export telemetry batch");
+        }
+
+        result
+    }
+}
+
+impl<E: SpanExporter> SpanExporter for InstrumentedExporter<E> {
+    async fn export(&self, batch: Vec<SpanData>) -> OTelSdkResult {
+        let items = batch.len();
+        let result = self.inner.export(batch).await;
+        self.record(result, items)
+    }
+
+    fn shutdown_with_timeout(&mut self, timeout: Duration) -> OTelSdkResult {
+        self.inner.shutdown_with_timeout(timeout)
+    }
+
+    fn force_flush(&mut self) -> OTelSdkResult {
+        self.inner.force_flush()
+    }
+
+    fn set_resource(&mut self, resource: &Resource) {
+        self.inner.set_resource(resource);
+    }
+}
+
+impl<E: LogExporter> LogExporter for InstrumentedExporter<E> {
+    async fn export(&self, batch: LogBatch<'_>) -> OTelSdkResult {
+        let items = batch.iter().count();
+        let result = self.inner.export(batch).await;
+        self.record(result, items)
+    }
+
+    fn shutdown_with_timeout(&self, timeout: Duration) -> OTelSdkResult {
+        self.inner.shutdown_with_timeout(timeout)
+    }
+
+    fn set_resource(&mut self, resource: &Resource) {
+        self.inner.set_resource(resource);
+    }
+}
+
+impl<E: PushMetricExporter> PushMetricExporter for InstrumentedExporter<E> {
+    async fn export(&self, metrics: &ResourceMetrics) -> OTelSdkResult {
+        let items = metrics
+            .scope_metrics()
+            .map(|scope| scope.metrics().count())
+            .sum();
+        let result = self.inner.export(metrics).await;
+        self.record(result, items)
+    }
+
+    fn force_flush(&self) -> OTelSdkResult {
+        self.inner.force_flush()
+    }
+
+    fn shutdown_with_timeout(&self, timeout: Duration) -> OTelSdkResult {
+        self.inner.shutdown_with_timeout(timeout)
+    }
+
+    fn temporality(&self) -> Temporality {
+        self.inner.temporality()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_is_suppressed() {
+        assert!(is_suppressed("h2"));
+        assert!(is_suppressed("h2::codec"));
+        assert!(is_suppressed("opentelemetry_otlp::exporter"));
+        assert!(is_suppressed("telemetry::export"));
+        assert!(!is_suppressed("h2c"));
+        assert!(!is_suppressed("tower_http::trace"));
+        assert!(!is_suppressed("telemetry::middleware::tracing"));
+    }
+}
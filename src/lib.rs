@@ -2,41 +2,269 @@
 //!
 //! It sets up tracing and metrics collection using OTLP exporters.
 
+pub mod build;
+mod build_info;
+mod export;
 mod metrics;
 pub mod middleware;
+mod resource;
 mod tracing;
 
+use ::tracing::{Subscriber, warn};
+pub use build_info::BuildInfo;
+#[doc(hidden)]
+pub use build_info::RUSTC_VERSION;
 use eyre::Result;
-use metrics::Metrics;
+pub use metrics::MetricView;
+use metrics::{Metrics, MetricsConfig};
 use opentelemetry::{KeyValue, Value};
-use opentelemetry_sdk::Resource;
-use std::env;
-use tracing::Tracing;
+pub use opentelemetry_sdk::metrics::{Aggregation, InstrumentKind, Temporality};
+use opentelemetry_sdk::{
+    Resource,
+    resource::{EnvResourceDetector, SdkProvidedResourceDetector, TelemetryResourceDetector},
+};
+use std::{env, time::Duration};
+pub use tracing::{FileLogConfig, Rotation};
+use tracing::{Tracing, TracingConfig};
+use tracing_subscriber::{
+    Layer, layer::SubscriberExt, registry, registry::LookupSpan, util::SubscriberInitExt,
+};
+
+/// Type-erased stack of the layers configured by [`TelemetryBuilder`].
+pub type TelemetryLayer<S> = Box<dyn Layer<S> + Send + Sync + 'static>;
 
 pub struct Telemetry {
-    _tracing: Tracing,
-    _metrics: Metrics,
+    tracing: Tracing,
+    metrics: Metrics,
+    build_info: Option<BuildInfo>,
 }
 
 impl Telemetry {
+    /// Installs the default configuration, see [`TelemetryBuilder::init`].
+    ///
+    /// `service.version` is only set by [`TelemetryBuilder::with_build_info`] or
+    /// [`TelemetryBuilder::with_service_version`], use
+    /// `Telemetry::builder(name).with_build_info(build_info!()).init()` to report it.
     pub fn init(name: impl Into<Value>) -> Result<Self> {
-        let resource = Resource::builder()
-            .with_service_name(name)
-            .with_attributes(vec![
-                KeyValue::new(
-                    "service.commit",
-                    env::var("GITHUB_SHA").unwrap_or_else(|_| "dev".to_string()),
-                ),
-                KeyValue::new(
-                    "service.environment",
-                    env::var("ENVIRONMENT").unwrap_or_else(|_| "dev".to_string()),
-                ),
+        Self::builder(name).init()
+    }
+
+    pub fn try_init(name: impl Into<Value>) -> Result<Self> {
+        Self::builder(name).try_init()
+    }
+
+    /// Sets the global tracer and meter providers, only called once every fallible step succeeded.
+    fn set_global(&self) {
+        self.tracing.set_global();
+        self.metrics.set_global();
+
+        if let Some(build_info) = self.build_info {
+            build_info.register_gauge();
+        }
+    }
+
+    /// Logs invalid configuration that fell back to defaults, done by [`TelemetryBuilder::init`]
+    /// and [`TelemetryBuilder::try_init`] once the subscriber is installed.
+    pub fn log_warnings(&self) {
+        for warning in &self.metrics.warnings {
+            warn!("{warning}");
+        }
+    }
+
+    pub fn builder(name: impl Into<Value>) -> TelemetryBuilder {
+        TelemetryBuilder {
+            name: name.into(),
+            version: None,
+            environment: None,
+            build_info: None,
+            tracing: TracingConfig::default(),
+            metrics: MetricsConfig::default(),
+        }
+    }
+}
+
+pub struct TelemetryBuilder {
+    name: Value,
+    version: Option<Value>,
+    environment: Option<Value>,
+    build_info: Option<BuildInfo>,
+    tracing: TracingConfig,
+    metrics: MetricsConfig,
+}
+
+impl TelemetryBuilder {
+    /// Sets `service.version`, usually `env!("CARGO_PKG_VERSION")` of the calling crate.
+    pub fn with_service_version(mut self, version: impl Into<Value>) -> Self {
+        self.version = Some(version.into());
+        self
+    }
+
+    /// Sets `service.environment`, defaults to the `ENVIRONMENT` variable.
+    pub fn with_environment(mut self, environment: impl Into<Value>) -> Self {
+        self.environment = Some(environment.into());
+        self
+    }
+
+    /// Uses the build metadata for `service.version` and `service.commit` and exports it as the
+    /// `build_info` gauge, see [`build_info!`].
+    ///
+    /// Without it the commit is read from the `GITHUB_SHA` variable at runtime. Unset attributes
+    /// can still be provided by `OTEL_RESOURCE_ATTRIBUTES`.
+    pub fn with_build_info(mut self, build_info: BuildInfo) -> Self {
+        self.build_info = Some(build_info);
+        self
+    }
+
+    /// Additionally writes the JSON console output to rolling files on disk.
+    pub fn with_file_logs(mut self, config: FileLogConfig) -> Self {
+        self.tracing.file = Some(config);
+        self
+    }
+
+    /// Overrides the filter of the console logger and the file sink, e.g. `info`.
+    pub fn with_console_filter(mut self, directives: impl Into<String>) -> Self {
+        self.tracing.console_filter = Some(directives.into());
+        self
+    }
+
+    /// Overrides the filter of the logs exported over OTLP, e.g. `info,h2=off,tonic=off`.
+    pub fn with_otlp_logs_filter(mut self, directives: impl Into<String>) -> Self {
+        self.tracing.otlp_logs_filter = Some(directives.into());
+        self
+    }
+
+    /// Overrides the filter of the spans exported over OTLP, e.g. `debug`.
+    pub fn with_otlp_traces_filter(mut self, directives: impl Into<String>) -> Self {
+        self.tracing.otlp_traces_filter = Some(directives.into());
+        self
+    }
+
+    /// Aggregates the given histograms as base-2 exponential histograms instead of explicit
+    /// buckets, `*` selects every histogram.
+    pub fn with_exponential_histograms<I>(mut self, instruments: I) -> Self
+    where
+        I: IntoIterator,
+        I::Item: Into<String>,
+    {
+        self.metrics.views.extend(
+            instruments
+                .into_iter()
+                .map(MetricView::exponential_histogram),
+        );
+        self
+    }
+
+    /// Sets the temporality of exported metrics, defaults to
+    /// `OTEL_EXPORTER_OTLP_METRICS_TEMPORALITY_PREFERENCE` or cumulative.
+    pub fn with_temporality(mut self, temporality: Temporality) -> Self {
+        self.metrics.temporality = Some(temporality);
+        self
+    }
+
+    /// Sets how often metrics are exported, defaults to `OTEL_METRIC_EXPORT_INTERVAL` or 60s.
+    pub fn with_export_interval(mut self, interval: Duration) -> Self {
+        self.metrics.interval = Some(interval);
+        self
+    }
+
+    /// Sets the timeout of a single metrics export, defaults to `OTEL_METRIC_EXPORT_TIMEOUT`,
+    /// `OTEL_EXPORTER_OTLP_METRICS_TIMEOUT`, `OTEL_EXPORTER_OTLP_TIMEOUT` or 10s.
+    pub fn with_export_timeout(mut self, timeout: Duration) -> Self {
+        self.metrics.timeout = Some(timeout);
+        self
+    }
+
+    /// Registers a view on the meter provider, see [`MetricView`].
+    ///
+    /// Views apply to the `jsonrpc` meter as well as application meters, and only the first
+    /// registered view matching an instrument is used.
+    pub fn with_view(mut self, view: MetricView) -> Self {
+        self.metrics.views.push(view);
+        self
+    }
+
+    /// Installs the configured layers as the global subscriber.
+    ///
+    /// # Panics
+    ///
+    /// Panics if a global subscriber has already been set, see [`TelemetryBuilder::try_init`].
+    pub fn init(self) -> Result<Telemetry> {
+        let (telemetry, layer) = self.build()?;
+        registry().with(layer).init();
+        telemetry.set_global();
+        telemetry.log_warnings();
+        Ok(telemetry)
+    }
+
+    /// Installs the configured layers as the global subscriber, returning an error instead of
+    /// panicking if one has already been set.
+    ///
+    /// The global providers are only set once the subscriber is installed, so an error leaves no
+    /// global state behind.
+    pub fn try_init(self) -> Result<Telemetry> {
+        let (telemetry, layer) = self.build()?;
+        registry().with(layer).try_init()?;
+        telemetry.set_global();
+        telemetry.log_warnings();
+        Ok(telemetry)
+    }
+
+    /// Sets up the exporters and returns the layers without installing a global subscriber, so
+    /// they can be composed with other layers into the caller's own registry.
+    ///
+    /// The returned [`Telemetry`] must be kept alive for as long as the layers are in use. Nothing
+    /// can be logged before the caller installs the layers, so configuration warnings are left to
+    /// [`Telemetry::log_warnings`].
+    pub fn layer<S>(self) -> Result<(Telemetry, TelemetryLayer<S>)>
+    where
+        S: Subscriber + for<'span> LookupSpan<'span> + Send + Sync,
+    {
+        let (telemetry, layer) = self.build()?;
+        telemetry.set_global();
+        Ok((telemetry, layer))
+    }
+
+    /// Sets up the exporters and layers without touching any global state.
+    fn build<S>(self) -> Result<(Telemetry, TelemetryLayer<S>)>
+    where
+        S: Subscriber + for<'span> LookupSpan<'span> + Send + Sync,
+    {
+        let version = self
+            .version
+            .or_else(|| Some(self.build_info?.version.into()));
+        let commit = self
+            .build_info
+            .and_then(|info| info.git_sha())
+            .map(Value::from)
+            .or_else(|| env::var("GITHUB_SHA").ok().map(Value::from));
+        let environment = self
+            .environment
+            .or_else(|| env::var("ENVIRONMENT").ok().map(Value::from));
+
+        // Later detectors take precedence, so `OTEL_RESOURCE_ATTRIBUTES` overrides detected values.
+        // Unknown values are left unset rather than defaulted, they would override it as well.
+        let resource = Resource::builder_empty()
+            .with_detectors(&resource::detectors())
+            .with_detectors(&[
+                Box::new(SdkProvidedResourceDetector),
+                Box::new(TelemetryResourceDetector),
+                Box::new(EnvResourceDetector::new()),
             ])
+            .with_service_name(self.name)
+            .with_attributes(version.map(|version| KeyValue::new("service.version", version)))
+            .with_attributes(commit.map(|commit| KeyValue::new("service.commit", commit)))
+            .with_attributes(
+                environment.map(|environment| KeyValue::new("service.environment", environment)),
+            )
             .build();
 
-        Ok(Self {
-            _tracing: Tracing::init(resource.clone())?,
-            _metrics: Metrics::init(resource)?,
-        })
+        let (tracing, layer) = Tracing::layer(resource.clone(), self.tracing)?;
+        let telemetry = Telemetry {
+            tracing,
+            metrics: Metrics::new(resource, self.metrics)?,
+            build_info: self.build_info,
+        };
+
+        Ok((telemetry, layer))
     }
 }
@@ -0,0 +1,196 @@
+//! Metrics module for OpenTelemetry integration.
+//!
+//! It sets up a meter provider with periodic exporting of metric data.
+
+mod view;
+
+use crate::export::InstrumentedExporter;
+use eyre::{Result, eyre};
+use global::set_meter_provider;
+use opentelemetry::global;
+use opentelemetry_otlp::{MetricExporter, WithExportConfig};
+use opentelemetry_sdk::{
+    Resource,
+    metrics::{Instrument, PeriodicReader, SdkMeterProvider, Temporality},
+};
+use std::{env, time::Duration};
+use tracing::error;
+pub use view::MetricView;
+
+const TEMPORALITY_PREFERENCE: &str = "OTEL_EXPORTER_OTLP_METRICS_TEMPORALITY_PREFERENCE";
+const EXPORT_INTERVAL: &str = "OTEL_METRIC_EXPORT_INTERVAL";
+const EXPORT_TIMEOUT: &[&str] = &[
+    "OTEL_METRIC_EXPORT_TIMEOUT",
+    "OTEL_EXPORTER_OTLP_METRICS_TIMEOUT",
+    "OTEL_EXPORTER_OTLP_TIMEOUT",
+];
+
+/// Unset values fall back to the standard `OTEL_*` environment variables and then the SDK defaults.
+#[derive(Default)]
+pub struct MetricsConfig {
+    /// Applied in order, the first view matching an instrument wins.
+    pub views: Vec<MetricView>,
+    pub temporality: Option<Temporality>,
+    pub interval: Option<Duration>,
+    pub timeout: Option<Duration>,
+}
+
+pub struct Metrics {
+    meter_provider: SdkMeterProvider,
+    /// Invalid environment variables, logged once a subscriber is installed
+    pub warnings: Vec<String>,
+}
+
+impl Metrics {
+    /// Builds the meter provider without setting it as the global one, see [`Metrics::set_global`].
+    pub fn new(resource: Resource, config: MetricsConfig) -> Result<Self> {
+        let mut warnings = Vec::new();
+        let env = |variable: &str| env::var(variable).ok();
+
+        let temporality = resolve(
+            config.temporality,
+            &[TEMPORALITY_PREFERENCE],
+            env,
+            parse_temporality,
+            &mut warnings,
+        )
+        .unwrap_or_default();
+        let interval = resolve(
+            config.interval,
+            &[EXPORT_INTERVAL],
+            env,
+            parse_millis,
+            &mut warnings,
+        );
+        let timeout = resolve(
+            config.timeout,
+            EXPORT_TIMEOUT,
+            env,
+            parse_millis,
+            &mut warnings,
+        );
+
+        // Resolved values are always passed on so that the SDK's own environment lookups can't
+        // override explicit configuration
+        let mut exporter = MetricExporter::builder()
+            .with_temporality(temporality)
+            .with_tonic();
+        if let Some(timeout) = timeout {
+            exporter = exporter.with_timeout(timeout);
+        }
+
+        // TODO: eyre::wrap_err?
+        let exporter = exporter.build().map_err(|error| {
+            error!(%error, "Failed to create OTLP Metric exporter");
+            error
+        })?;
+
+        let mut reader = PeriodicReader::builder(InstrumentedExporter::new(exporter, "metrics"));
+        if let Some(interval) = interval {
+            reader = reader.with_interval(interval);
+        }
+
+        let mut builder = SdkMeterProvider::builder()
+            .with_reader(reader.build())
+            .with_resource(resource);
+
+        if !config.views.is_empty() {
+            // Fail early on invalid views instead of silently exporting the default stream
+            for view in &config.views {
+                view.stream()
+                    .map_err(|error| eyre!("Invalid metric view {view:?}: {error}"))?;
+            }
+
+            // A single view so that an instrument never produces more than one stream
+            let views = config.views;
+            builder = builder.with_view(move |instrument: &Instrument| {
+                views
+                    .iter()
+                    .find(|view| view.matches(instrument))
+                    .and_then(|view| view.stream().ok())
+            });
+        }
+
+        Ok(Self {
+            meter_provider: builder.build(),
+            warnings,
+        })
+    }
+
+    pub fn set_global(&self) {
+        set_meter_provider(self.meter_provider.clone());
+    }
+}
+
+/// Explicit configuration wins, then the first set environment variable in order.
+///
+/// Invalid values are skipped with a warning instead of failing the setup.
+fn resolve<T>(
+    explicit: Option<T>,
+    variables: &[&str],
+    env: impl Fn(&str) -> Option<String>,
+    parse: impl Fn(&str) -> Option<T>,
+    warnings: &mut Vec<String>,
+) -> Option<T> {
+    explicit.or_else(|| {
+        variables.iter().find_map(|variable| {
+            let value = env(variable)?;
+            let parsed = parse(&value);
+            if parsed.is_none() {
+                warnings.push(format!("Ignoring invalid {variable}={value:?}"));
+            }
+            parsed
+        })
+    })
+}
+
+/// Parses the values of `OTEL_EXPORTER_OTLP_METRICS_TEMPORALITY_PREFERENCE`.
+fn parse_temporality(value: &str) -> Option<Temporality> {
+    match value.to_lowercase().as_str() {
+        "cumulative" => Some(Temporality::Cumulative),
+        "delta" => Some(Temporality::Delta),
+        "lowmemory" => Some(Temporality::LowMemory),
+        _ => None,
+    }
+}
+
+/// Parses the millisecond durations of the `OTEL_*` variables, zero isn't a valid interval.
+fn parse_millis(value: &str) -> Option<Duration> {
+    let millis = value.trim().parse().ok().filter(|&millis| millis > 0)?;
+    Some(Duration::from_millis(millis))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_temporality() {
+        assert_eq!(parse_temporality("delta"), Some(Temporality::Delta));
+        assert_eq!(
+            parse_temporality("Cumulative"),
+            Some(Temporality::Cumulative)
+        );
+        assert_eq!(parse_temporality("LOWMEMORY"), Some(Temporality::LowMemory));
+        assert_eq!(parse_temporality("sometimes"), None);
+    }
+
+    #[test]
+    fn test_resolve_precedence() {
+        let env = |variable: &str| match variable {
+            "OTEL_METRIC_EXPORT_TIMEOUT" => Some("soon".to_string()),
+            "OTEL_EXPORTER_OTLP_METRICS_TIMEOUT" => Some("2000".to_string()),
+            _ => None,
+        };
+        let mut warnings = Vec::new();
+
+        let explicit = Some(Duration::from_secs(5));
+        let timeout = resolve(explicit, EXPORT_TIMEOUT, env, parse_millis, &mut warnings);
+        assert_eq!(timeout, explicit);
+        assert!(warnings.is_empty());
+
+        let timeout = resolve(None, EXPORT_TIMEOUT, env, parse_millis, &mut warnings);
+        assert_eq!(timeout, Some(Duration::from_secs(2)));
+        assert_eq!(warnings.len(), 1);
+    }
+}
@@ -0,0 +1,200 @@
+//! Declarative views applied to every instrument of the meter provider.
+
+use opentelemetry::Key;
+use opentelemetry_sdk::metrics::{Aggregation, Instrument, InstrumentKind, Stream};
+
+/// Bucket count and scale used for exponential histograms, matching the SDK defaults
+const EXPONENTIAL_MAX_SIZE: u32 = 160;
+const EXPONENTIAL_MAX_SCALE: i8 = 20;
+
+/// Selects instruments by name, meter or kind and changes how they are exported.
+///
+/// Names support `*` wildcards, e.g. `jsonrpc_*`. Only the first view matching an instrument is
+/// applied, instruments matched by no view are exported unchanged.
+#[derive(Clone, Debug, Default)]
+pub struct MetricView {
+    instrument: Option<String>,
+    meter: Option<String>,
+    kind: Option<InstrumentKind>,
+    rename: Option<String>,
+    allowed_attributes: Option<Vec<String>>,
+    aggregation: Option<Aggregation>,
+}
+
+impl MetricView {
+    /// Matches instruments with the given name.
+    pub fn instrument(name: impl Into<String>) -> Self {
+        Self {
+            instrument: Some(name.into()),
+            ..Self::default()
+        }
+    }
+
+    /// Matches every instrument created by the given meter, e.g. `jsonrpc`.
+    pub fn meter(name: impl Into<String>) -> Self {
+        Self {
+            meter: Some(name.into()),
+            ..Self::default()
+        }
+    }
+
+    /// Aggregates the matching histograms as base-2 exponential histograms.
+    pub fn exponential_histogram(instrument: impl Into<String>) -> Self {
+        Self::instrument(instrument)
+            .with_kind(InstrumentKind::Histogram)
+            .aggregation(Aggregation::Base2ExponentialHistogram {
+                max_size: EXPONENTIAL_MAX_SIZE,
+                max_scale: EXPONENTIAL_MAX_SCALE,
+                record_min_max: true,
+            })
+    }
+
+    /// Additionally requires the instrument to be created by the given meter.
+    pub fn with_meter(mut self, name: impl Into<String>) -> Self {
+        self.meter = Some(name.into());
+        self
+    }
+
+    /// Additionally requires the instrument to be of the given kind.
+    pub fn with_kind(mut self, kind: InstrumentKind) -> Self {
+        self.kind = Some(kind);
+        self
+    }
+
+    /// Exports the instrument under a different name.
+    ///
+    /// Requires an exact instrument name, a wildcard would map several instruments onto the same
+    /// stream and is rejected when the meter provider is built.
+    pub fn rename(mut self, name: impl Into<String>) -> Self {
+        self.rename = Some(name.into());
+        self
+    }
+
+    /// Drops every attribute not in the allowlist.
+    pub fn allow_attributes<I>(mut self, keys: I) -> Self
+    where
+        I: IntoIterator,
+        I::Item: Into<String>,
+    {
+        self.allowed_attributes = Some(keys.into_iter().map(Into::into).collect());
+        self
+    }
+
+    pub fn aggregation(mut self, aggregation: Aggregation) -> Self {
+        self.aggregation = Some(aggregation);
+        self
+    }
+
+    /// Disables the instrument entirely.
+    pub fn discard(self) -> Self {
+        self.aggregation(Aggregation::Drop)
+    }
+
+    pub(crate) fn matches(&self, instrument: &Instrument) -> bool {
+        self.instrument
+            .as_ref()
+            .is_none_or(|pattern| wildcard_match(pattern, instrument.name()))
+            && self
+                .meter
+                .as_ref()
+                .is_none_or(|pattern| wildcard_match(pattern, instrument.scope().name()))
+            && self.kind.is_none_or(|kind| kind == instrument.kind())
+    }
+
+    pub(crate) fn stream(&self) -> Result<Stream, Box<dyn std::error::Error>> {
+        let mut builder = Stream::builder();
+
+        if let Some(name) = &self.rename {
+            if self
+                .instrument
+                .as_ref()
+                .is_none_or(|pattern| pattern.contains('*'))
+            {
+                return Err(
+                    format!("renaming to {name:?} requires an exact instrument name").into(),
+                );
+            }
+
+            builder = builder.with_name(name.clone());
+        }
+        if let Some(keys) = &self.allowed_attributes {
+            builder = builder.with_allowed_attribute_keys(keys.iter().cloned().map(Key::new));
+        }
+        if let Some(aggregation) = &self.aggregation {
+            builder = builder.with_aggregation(aggregation.clone());
+        }
+
+        builder.build()
+    }
+}
+
+/// Matches `value` against `pattern`, where `*` matches any sequence of characters.
+fn wildcard_match(pattern: &str, value: &str) -> bool {
+    let mut parts = pattern.split('*');
+    let first = parts.next().unwrap_or_default();
+
+    let Some(mut rest) = value.strip_prefix(first) else {
+        return false;
+    };
+
+    let mut parts = parts.peekable();
+    while let Some(part) = parts.next() {
+        if parts.peek().is_none() {
+            return rest.ends_with(part);
+        }
+        match rest.find(part) {
+            Some(index) => rest = &rest[index + part.len()..],
+            None => return false,
+        }
+    }
+
+    rest.is_empty()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_wildcard_match() {
+        assert!(wildcard_match(
+            "jsonrpc_method_calls",
+            "jsonrpc_method_calls"
+        ));
+        assert!(!wildcard_match(
+            "jsonrpc_method_calls",
+            "jsonrpc_method_calls_total"
+        ));
+        assert!(wildcard_match("*", "anything"));
+        assert!(wildcard_match("jsonrpc_*", "jsonrpc_method_latency"));
+        assert!(!wildcard_match("jsonrpc_*", "http_server_duration"));
+        assert!(wildcard_match("*_latency", "jsonrpc_method_latency"));
+        assert!(wildcard_match(
+            "jsonrpc_*_latency",
+            "jsonrpc_method_latency"
+        ));
+        assert!(!wildcard_match("jsonrpc_*_latency", "jsonrpc_method_calls"));
+    }
+
+    #[test]
+    fn test_rename_requires_exact_instrument() {
+        assert!(
+            MetricView::instrument("jsonrpc_method_calls")
+                .rename("calls")
+                .stream()
+                .is_ok()
+        );
+        assert!(
+            MetricView::instrument("jsonrpc_*")
+                .rename("calls")
+                .stream()
+                .is_err()
+        );
+        assert!(
+            MetricView::meter("jsonrpc")
+                .rename("calls")
+                .stream()
+                .is_err()
+        );
+    }
+}
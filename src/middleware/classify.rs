@@ -0,0 +1,259 @@
+//! Classification of JSON-RPC error responses as failures.
+//!
+//! JSON-RPC errors are returned with a `200 OK` status, so tower-http classifiers which only see
+//! the response head can't tell them apart from results, and tower-http doesn't classify bodies at
+//! their end. [`JsonRpcErrorLayer`] therefore reads the error code while the body streams, without
+//! holding it back, and reports the error as a failure of the request span once the body ends.
+//! It reports on the current span, so [`trace_layer`](super::trace_layer) runs it within the request
+//! span.
+//!
+//! Only the first 64 KiB of a body are kept, larger bodies are taken for results.
+
+use crate::middleware::{inspect_response, is_json_rpc, tracing::record_failure};
+use axum::{
+    body::Body,
+    http::{Request, Response},
+};
+use futures_util::future::BoxFuture;
+use std::{
+    convert::Infallible,
+    fmt,
+    task::{Context, Poll},
+    time::Instant,
+};
+use tower::{Layer, Service};
+use tower_http::classify::{
+    ClassifiedResponse, ClassifyResponse, NeverClassifyEos, ServerErrorsAsFailures,
+    ServerErrorsFailureClass,
+};
+use tracing::{Level, Span};
+
+/// Error code of a JSON-RPC error response.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct JsonRpcError {
+    pub code: i64,
+}
+
+/// Logs JSON-RPC errors at error level, [`TraceLayerBuilder::build`](super::TraceLayerBuilder::build)
+/// adds it at the failure level of the trace layer.
+#[derive(Clone, Copy)]
+pub struct JsonRpcErrorLayer {
+    failure_level: Level,
+}
+
+impl Default for JsonRpcErrorLayer {
+    fn default() -> Self {
+        Self {
+            failure_level: Level::ERROR,
+        }
+    }
+}
+
+impl JsonRpcErrorLayer {
+    /// Level of the "Request Failed" log of JSON-RPC errors.
+    pub fn failure_level(mut self, level: Level) -> Self {
+        self.failure_level = level;
+        self
+    }
+}
+
+impl<S> Layer<S> for JsonRpcErrorLayer {
+    type Service = JsonRpcErrorInspector<S>;
+    fn layer(&self, inner: S) -> Self::Service {
+        JsonRpcErrorInspector {
+            inner,
+            failure_level: self.failure_level,
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct JsonRpcErrorInspector<S> {
+    inner: S,
+    failure_level: Level,
+}
+
+impl<S> Service<Request<Body>> for JsonRpcErrorInspector<S>
+where
+    S: Service<Request<Body>, Response = Response<Body>, Error = Infallible>
+        + Clone
+        + Send
+        + 'static,
+    S::Future: Send + 'static,
+{
+    type Response = S::Response;
+    type Error = Infallible;
+    type Future = BoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, request: Request<Body>) -> Self::Future {
+        let mut inner = self.inner.clone();
+        if !is_json_rpc(&request) {
+            return Box::pin(async move { inner.call(request).await });
+        }
+        let failure_level = self.failure_level;
+        // Entered by the trace layer while calling its inner service
+        let span = Span::current();
+        let start = Instant::now();
+
+        Box::pin(async move {
+            let response = inner.call(request).await?;
+            // Already reported by the trace layer classifier
+            if response.status().is_server_error() {
+                return Ok(response);
+            }
+
+            Ok(inspect_response(response, move |_, code| {
+                if let Some(code) = code {
+                    let class = JsonRpcFailureClass::JsonRpc(JsonRpcError { code });
+                    record_failure(failure_level, class, start.elapsed(), &span);
+                }
+            }))
+        })
+    }
+}
+
+/// Failure classes of [`ServerErrorClassifier`] and [`JsonRpcErrorLayer`].
+#[derive(Debug)]
+pub enum JsonRpcFailureClass {
+    /// Server error status or service error, as classified by [`ServerErrorsAsFailures`].
+    Http(ServerErrorsFailureClass),
+    /// Successful status with a JSON-RPC error in the body.
+    JsonRpc(JsonRpcError),
+}
+
+impl fmt::Display for JsonRpcFailureClass {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Http(class) => class.fmt(f),
+            Self::JsonRpc(error) => write!(f, "JSON-RPC error {}", error.code),
+        }
+    }
+}
+
+/// Classifies server errors as failures, JSON-RPC errors are reported by [`JsonRpcErrorLayer`].
+#[derive(Clone, Copy, Debug, Default)]
+pub struct ServerErrorClassifier;
+
+impl ClassifyResponse for ServerErrorClassifier {
+    type FailureClass = JsonRpcFailureClass;
+    type ClassifyEos = NeverClassifyEos<JsonRpcFailureClass>;
+
+    fn classify_response<B>(
+        self,
+        response: &Response<B>,
+    ) -> ClassifiedResponse<Self::FailureClass, Self::ClassifyEos> {
+        let class = match ServerErrorsAsFailures::new().classify_response(response) {
+            ClassifiedResponse::Ready(Err(class)) => Err(JsonRpcFailureClass::Http(class)),
+            _ => Ok(()),
+        };
+
+        ClassifiedResponse::Ready(class)
+    }
+
+    fn classify_error<E>(self, error: &E) -> Self::FailureClass
+    where
+        E: fmt::Display + 'static,
+    {
+        JsonRpcFailureClass::Http(ServerErrorsAsFailures::new().classify_error(error))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::{body::to_bytes, http::StatusCode};
+    use std::sync::{Arc, Mutex};
+    use tower::{ServiceExt, service_fn};
+    use tracing::{
+        Event, Subscriber,
+        field::{Field, Visit},
+    };
+    use tracing_subscriber::{layer::SubscriberExt, registry};
+
+    /// Collects the `error` field of logged failures.
+    #[derive(Clone, Default)]
+    struct Failures(Arc<Mutex<Vec<String>>>);
+
+    impl Visit for Failures {
+        fn record_str(&mut self, field: &Field, value: &str) {
+            if field.name() == "error" {
+                self.0.lock().unwrap().push(value.to_string());
+            }
+        }
+
+        fn record_debug(&mut self, _field: &Field, _value: &dyn fmt::Debug) {}
+    }
+
+    impl<S: Subscriber> tracing_subscriber::Layer<S> for Failures {
+        fn on_event(&self, event: &Event<'_>, _ctx: tracing_subscriber::layer::Context<'_, S>) {
+            event.record(&mut self.clone());
+        }
+    }
+
+    fn classify(response: &Response<Body>) -> Result<(), JsonRpcFailureClass> {
+        match ServerErrorClassifier.classify_response(response) {
+            ClassifiedResponse::Ready(class) => class,
+            ClassifiedResponse::RequiresEos(_) => unreachable!(),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_classifies_jsonrpc_errors() {
+        let failures = Failures::default();
+        let _guard = tracing::subscriber::set_default(registry().with(failures.clone()));
+
+        let service =
+            JsonRpcErrorLayer::default().layer(service_fn(|request: Request<Body>| async move {
+                Ok::<_, Infallible>(Response::new(request.into_body()))
+            }));
+        let request = |body: &'static str| {
+            Request::post("/")
+                .header("content-type", "application/json")
+                .body(Body::from(body))
+                .unwrap()
+        };
+
+        let error = r#"{"jsonrpc":"2.0","id":1,"error":{"code":-32000,"message":"x"}}"#;
+        let response = service.clone().oneshot(request(error)).await.unwrap();
+        // Reported once the body has been streamed
+        assert!(failures.0.lock().unwrap().is_empty());
+        let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        assert_eq!(body, error);
+        assert_eq!(*failures.0.lock().unwrap(), ["JSON-RPC error -32000"]);
+
+        let result = r#"{"jsonrpc":"2.0","id":1,"result":"0x1"}"#;
+        let response = service.oneshot(request(result)).await.unwrap();
+        to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        assert_eq!(failures.0.lock().unwrap().len(), 1);
+
+        let mut response = Response::new(Body::empty());
+        *response.status_mut() = StatusCode::BAD_GATEWAY;
+        assert!(matches!(
+            classify(&response),
+            Err(JsonRpcFailureClass::Http(
+                ServerErrorsFailureClass::StatusCode(StatusCode::BAD_GATEWAY)
+            ))
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_trace_layer_reports_jsonrpc_errors() {
+        let failures = Failures::default();
+        let _guard = tracing::subscriber::set_default(registry().with(failures.clone()));
+
+        let service = crate::middleware::trace_layer().layer(service_fn(|_| async {
+            let error = r#"{"jsonrpc":"2.0","id":1,"error":{"code":-32000,"message":"x"}}"#;
+            Ok::<_, Infallible>(Response::new(Body::from(error)))
+        }));
+        let request = Request::post("/").body(Body::empty()).unwrap();
+        let response = service.oneshot(request).await.unwrap();
+        to_bytes(Body::new(response.into_body()), usize::MAX)
+            .await
+            .unwrap();
+        assert_eq!(*failures.0.lock().unwrap(), ["JSON-RPC error -32000"]);
+    }
+}
@@ -0,0 +1,490 @@
+//! Middleware for identifying the client of a request for per-client attribution.
+//!
+//! The identity is resolved from the configured sources in order and inserted as a
+//! [`ClientIdentity`] extension. It is recorded on the `client` field of the
+//! [`trace_layer`](super::trace_layer) span and, when enabled, added as a `client` attribute to the
+//! JSON-RPC metrics. The layer must therefore be added inside the trace layer, whose span must
+//! already exist, and outside the metric layers.
+//!
+//! Only the first `max_clients` distinct identities of each label window get their own metric
+//! label, later ones are reported as `other` so that a flood of random API keys or addresses can't
+//! explode cardinality. The labels are reset at the end of every window so that clients arriving
+//! later get their own label too.
+//!
+//! Forwarding headers and baggage are set by the client, so only the peer address is used by
+//! default and `X-Forwarded-For` is only read from configured trusted proxies.
+
+use crate::middleware::HeaderExtractor;
+use axum::{
+    body::Body,
+    extract::ConnectInfo,
+    http::{Extensions, HeaderMap, HeaderName, Request},
+    response::Response,
+};
+use futures_util::future::BoxFuture;
+use hmac::{Hmac, Mac};
+use opentelemetry::{KeyValue, baggage::BaggageExt, propagation::TextMapPropagator};
+use opentelemetry_sdk::propagation::BaggagePropagator;
+use sha2::Sha256;
+use std::{
+    collections::HashSet,
+    convert::Infallible,
+    fmt::Write,
+    net::{IpAddr, SocketAddr},
+    sync::{Arc, Mutex},
+    task::{Context, Poll},
+    time::{Duration, Instant},
+};
+use tower::{Layer, Service};
+use tracing::Span;
+use uuid::Uuid;
+
+/// Identity of requests not matched by any source
+const ANONYMOUS: &str = "anonymous";
+/// Label of identities beyond `max_clients`
+const OTHER: &str = "other";
+const DEFAULT_MAX_CLIENTS: usize = 100;
+const DEFAULT_LABEL_WINDOW: Duration = Duration::from_secs(60 * 60);
+/// Bytes of the HMAC kept in API key ids
+const API_KEY_ID_LEN: usize = 8;
+
+/// Where the identity of a client is read from.
+#[derive(Clone, Debug)]
+pub enum ClientIdSource {
+    /// API key header, reported as a salted HMAC-SHA256 of the key rather than the secret itself.
+    ApiKey(HeaderName),
+    /// Peer address from [`ConnectInfo`].
+    PeerIp,
+    /// Client address from `X-Forwarded-For` for requests from a trusted proxy, else the peer
+    /// address.
+    ///
+    /// The header is read from the right, skipping the addresses of trusted proxies, since every
+    /// other entry can be set by the client. Without
+    /// [`trusted_proxies`](ClientIdentityLayerBuilder::trusted_proxies) it is the peer address.
+    ForwardedIp,
+    /// Entry of the W3C `baggage` header.
+    ///
+    /// Baggage is set by the caller, only use it when every caller is trusted.
+    Baggage(String),
+}
+
+/// Client of a request, inserted as an extension by [`ClientIdentityLayer`].
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ClientIdentity {
+    id: String,
+    label: String,
+}
+
+impl ClientIdentity {
+    /// Full identity such as `key:<hash>`, `ip:<address>` or `tenant:<name>`.
+    pub fn id(&self) -> &str {
+        &self.id
+    }
+
+    /// Bounded cardinality label used as the `client` metric attribute.
+    pub fn label(&self) -> &str {
+        &self.label
+    }
+}
+
+#[derive(Clone)]
+pub struct ClientIdentityLayer {
+    sources: Arc<[ClientIdSource]>,
+    api_key_salt: Arc<[u8]>,
+    trusted_proxies: Arc<HashSet<IpAddr>>,
+    max_clients: usize,
+    label_window: Duration,
+    labels: Arc<Mutex<Labels>>,
+}
+
+/// Identities labeled individually in the current window.
+struct Labels {
+    started: Instant,
+    ids: HashSet<String>,
+}
+
+impl ClientIdentityLayer {
+    pub fn builder() -> ClientIdentityLayerBuilder {
+        ClientIdentityLayerBuilder {
+            sources: Vec::new(),
+            api_key_salt: None,
+            trusted_proxies: HashSet::new(),
+            max_clients: DEFAULT_MAX_CLIENTS,
+            label_window: DEFAULT_LABEL_WINDOW,
+        }
+    }
+
+    fn identify(&self, request: &Request<Body>) -> ClientIdentity {
+        let id = self
+            .sources
+            .iter()
+            .find_map(|source| self.identify_with(source, request))
+            .unwrap_or_else(|| ANONYMOUS.to_string());
+        let label = self.label(&id, Instant::now());
+        ClientIdentity { id, label }
+    }
+
+    fn identify_with(&self, source: &ClientIdSource, request: &Request<Body>) -> Option<String> {
+        match source {
+            ClientIdSource::ApiKey(header) => {
+                let key = request.headers().get(header)?.as_bytes();
+                (!key.is_empty()).then(|| api_key_id(&self.api_key_salt, key))
+            }
+            ClientIdSource::PeerIp => peer_address(request).map(|ip| format!("ip:{ip}")),
+            ClientIdSource::ForwardedIp => {
+                forwarded_client(request, &self.trusted_proxies).map(|ip| format!("ip:{ip}"))
+            }
+            ClientIdSource::Baggage(key) => {
+                let context = BaggagePropagator::new().extract(&HeaderExtractor(request.headers()));
+                let value = context.baggage().get(key)?.as_str().trim();
+                (!value.is_empty()).then(|| format!("{key}:{value}"))
+            }
+        }
+    }
+
+    fn label(&self, id: &str, now: Instant) -> String {
+        let Ok(mut labels) = self.labels.lock() else {
+            return OTHER.to_string();
+        };
+
+        if now.duration_since(labels.started) >= self.label_window {
+            labels.started = now;
+            labels.ids.clear();
+        }
+
+        if labels.ids.contains(id) || id == ANONYMOUS {
+            return id.to_string();
+        }
+
+        if labels.ids.len() < self.max_clients {
+            labels.ids.insert(id.to_string());
+            id.to_string()
+        } else {
+            OTHER.to_string()
+        }
+    }
+}
+
+/// Identifies clients by their peer address.
+impl Default for ClientIdentityLayer {
+    fn default() -> Self {
+        Self::builder().peer_ip().build()
+    }
+}
+
+/// Configures the identity sources, tried in the order they are added.
+pub struct ClientIdentityLayerBuilder {
+    sources: Vec<ClientIdSource>,
+    api_key_salt: Option<Vec<u8>>,
+    trusted_proxies: HashSet<IpAddr>,
+    max_clients: usize,
+    label_window: Duration,
+}
+
+impl ClientIdentityLayerBuilder {
+    pub fn source(mut self, source: ClientIdSource) -> Self {
+        self.sources.push(source);
+        self
+    }
+
+    pub fn api_key_header(self, header: HeaderName) -> Self {
+        self.source(ClientIdSource::ApiKey(header))
+    }
+
+    pub fn peer_ip(self) -> Self {
+        self.source(ClientIdSource::PeerIp)
+    }
+
+    pub fn forwarded_ip(self) -> Self {
+        self.source(ClientIdSource::ForwardedIp)
+    }
+
+    pub fn baggage(self, key: impl Into<String>) -> Self {
+        self.source(ClientIdSource::Baggage(key.into()))
+    }
+
+    /// Secret key of the HMAC applied to API keys.
+    ///
+    /// Defaults to a random salt per process, configure a shared one for ids that are stable
+    /// across restarts and replicas.
+    pub fn api_key_salt(mut self, salt: impl Into<Vec<u8>>) -> Self {
+        self.api_key_salt = Some(salt.into());
+        self
+    }
+
+    /// Addresses of the proxies whose `X-Forwarded-For` entries are trusted by
+    /// [`ClientIdSource::ForwardedIp`].
+    pub fn trusted_proxies(mut self, proxies: impl IntoIterator<Item = IpAddr>) -> Self {
+        self.trusted_proxies.extend(proxies);
+        self
+    }
+
+    /// Number of distinct identities labeled individually per label window, defaults to 100.
+    pub fn max_clients(mut self, max_clients: usize) -> Self {
+        self.max_clients = max_clients;
+        self
+    }
+
+    /// Length of the window after which the labeled identities are reset, defaults to an hour.
+    pub fn label_window(mut self, window: Duration) -> Self {
+        self.label_window = window;
+        self
+    }
+
+    pub fn build(self) -> ClientIdentityLayer {
+        let api_key_salt = self
+            .api_key_salt
+            .unwrap_or_else(|| Uuid::new_v4().as_bytes().to_vec());
+        ClientIdentityLayer {
+            sources: self.sources.into(),
+            api_key_salt: api_key_salt.into(),
+            trusted_proxies: Arc::new(self.trusted_proxies),
+            max_clients: self.max_clients,
+            label_window: self.label_window,
+            labels: Arc::new(Mutex::new(Labels {
+                started: Instant::now(),
+                ids: HashSet::new(),
+            })),
+        }
+    }
+}
+
+impl<S> Layer<S> for ClientIdentityLayer {
+    type Service = ClientIdentityService<S>;
+    fn layer(&self, inner: S) -> Self::Service {
+        ClientIdentityService {
+            inner,
+            layer: self.clone(),
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct ClientIdentityService<S> {
+    inner: S,
+    layer: ClientIdentityLayer,
+}
+
+impl<S> Service<Request<Body>> for ClientIdentityService<S>
+where
+    S: Service<Request<Body>, Response = Response, Error = Infallible> + Clone + Send + 'static,
+    S::Future: Send + 'static,
+{
+    type Response = S::Response;
+    type Error = Infallible;
+    type Future = BoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, mut request: Request<Body>) -> Self::Future {
+        let identity = self.layer.identify(&request);
+        Span::current().record("client", identity.id());
+        request.extensions_mut().insert(identity);
+
+        let mut inner = self.inner.clone();
+        Box::pin(async move { inner.call(request).await })
+    }
+}
+
+/// `client` metric attribute of a request, `anonymous` without a [`ClientIdentity`].
+pub(crate) fn client_attribute(extensions: &Extensions) -> KeyValue {
+    let label = extensions
+        .get::<ClientIdentity>()
+        .map_or(ANONYMOUS, ClientIdentity::label);
+    KeyValue::new("client", label.to_string())
+}
+
+/// Truncated HMAC of the key, stable for a given salt and not reversible without it.
+fn api_key_id(salt: &[u8], key: &[u8]) -> String {
+    let Ok(mut mac) = Hmac::<Sha256>::new_from_slice(salt) else {
+        unreachable!("HMAC accepts keys of any length");
+    };
+    mac.update(key);
+    let hash = mac.finalize().into_bytes();
+
+    hash[..API_KEY_ID_LEN]
+        .iter()
+        .fold(String::from("key:"), |mut id, byte| {
+            let _ = write!(id, "{byte:02x}");
+            id
+        })
+}
+
+/// First address of `X-Forwarded-For`, else the peer address from [`ConnectInfo`].
+pub(crate) fn client_address<B>(request: &Request<B>) -> Option<IpAddr> {
+    forwarded_ip(request.headers()).or_else(|| peer_address(request))
+}
+
+fn peer_address<B>(request: &Request<B>) -> Option<IpAddr> {
+    request
+        .extensions()
+        .get::<ConnectInfo<SocketAddr>>()
+        .map(|ConnectInfo(address)| address.ip())
+}
+
+/// Rightmost `X-Forwarded-For` address that isn't a trusted proxy when the peer is one, else the
+/// peer address.
+fn forwarded_client<B>(request: &Request<B>, trusted_proxies: &HashSet<IpAddr>) -> Option<IpAddr> {
+    let peer = peer_address(request)?;
+    if !trusted_proxies.contains(&peer) {
+        return Some(peer);
+    }
+
+    let forwarded = request
+        .headers()
+        .get_all("x-forwarded-for")
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .map(|address| address.trim().parse::<IpAddr>().ok())
+        .collect::<Vec<_>>();
+    // Stop at the first unparseable entry, anything left of it can't be attributed
+    forwarded
+        .into_iter()
+        .rev()
+        .map_while(|address| address)
+        .find(|address| !trusted_proxies.contains(address))
+        .or(Some(peer))
+}
+
+fn forwarded_ip(headers: &HeaderMap) -> Option<IpAddr> {
+    headers
+        .get("x-forwarded-for")?
+        .to_str()
+        .ok()?
+        .split(',')
+        .next()?
+        .trim()
+        .parse()
+        .ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::http::request;
+    use tower::{ServiceExt, service_fn};
+
+    async fn identify(layer: ClientIdentityLayer, request: Request<Body>) -> ClientIdentity {
+        let service = layer.layer(service_fn(|request: Request<Body>| async move {
+            let identity = request.extensions().get::<ClientIdentity>().cloned();
+            let mut response = Response::new(Body::empty());
+            response.extensions_mut().insert(identity);
+            Ok::<_, Infallible>(response)
+        }));
+
+        let response = service.oneshot(request).await.unwrap();
+        response
+            .extensions()
+            .get::<Option<ClientIdentity>>()
+            .cloned()
+            .flatten()
+            .unwrap()
+    }
+
+    fn from_peer(peer: [u8; 4], builder: request::Builder) -> Request<Body> {
+        let mut request = builder.body(Body::empty()).unwrap();
+        request
+            .extensions_mut()
+            .insert(ConnectInfo(SocketAddr::from((peer, 4000))));
+        request
+    }
+
+    #[tokio::test]
+    async fn test_sources_in_order() {
+        let layer = || {
+            ClientIdentityLayer::builder()
+                .api_key_header(HeaderName::from_static("x-api-key"))
+                .baggage("tenant")
+                .peer_ip()
+                .build()
+        };
+
+        let request = from_peer(
+            [192, 0, 2, 1],
+            Request::builder().header("baggage", "tenant=acme,region=eu"),
+        );
+        let identity = identify(layer(), request).await;
+        assert_eq!(identity.id(), "tenant:acme");
+
+        let request = from_peer([192, 0, 2, 1], Request::builder());
+        let identity = identify(layer(), request).await;
+        assert_eq!(identity.id(), "ip:192.0.2.1");
+
+        let request = Request::builder()
+            .header("x-api-key", "secret")
+            .body(Body::empty())
+            .unwrap();
+        let identity = identify(layer(), request).await;
+        assert!(identity.id().starts_with("key:"));
+        assert!(!identity.id().contains("secret"));
+
+        // Stable for a given salt across processes and toolchains
+        assert_eq!(
+            api_key_id(b"salt", b"secret"),
+            api_key_id(b"salt", b"secret")
+        );
+        assert_eq!(api_key_id(b"salt", b"secret").len(), 4 + 2 * API_KEY_ID_LEN);
+        assert_ne!(
+            api_key_id(b"salt", b"secret"),
+            api_key_id(b"pepper", b"secret")
+        );
+
+        let identity = identify(layer(), Request::default()).await;
+        assert_eq!(identity.id(), ANONYMOUS);
+    }
+
+    #[tokio::test]
+    async fn test_untrusted_headers_are_ignored() {
+        let spoofed = || {
+            Request::builder()
+                .header("x-forwarded-for", "203.0.113.7")
+                .header("baggage", "tenant=acme")
+        };
+
+        let request = from_peer([192, 0, 2, 1], spoofed());
+        let identity = identify(ClientIdentityLayer::default(), request).await;
+        assert_eq!(identity.id(), "ip:192.0.2.1");
+
+        let layer = || {
+            ClientIdentityLayer::builder()
+                .forwarded_ip()
+                .trusted_proxies([IpAddr::from([10, 0, 0, 1]), IpAddr::from([10, 0, 0, 2])])
+                .build()
+        };
+
+        // Only trusted proxies can forward an address
+        let request = from_peer([192, 0, 2, 1], spoofed());
+        let identity = identify(layer(), request).await;
+        assert_eq!(identity.id(), "ip:192.0.2.1");
+
+        // Entries left of the client address were sent by the client
+        let request = from_peer(
+            [10, 0, 0, 1],
+            Request::builder().header("x-forwarded-for", "198.51.100.1, 203.0.113.7, 10.0.0.2"),
+        );
+        let identity = identify(layer(), request).await;
+        assert_eq!(identity.id(), "ip:203.0.113.7");
+    }
+
+    #[test]
+    fn test_label_is_bounded() {
+        let layer = ClientIdentityLayer::builder()
+            .max_clients(2)
+            .label_window(Duration::from_secs(60))
+            .build();
+        let now = Instant::now();
+
+        assert_eq!(layer.label("ip:192.0.2.1", now), "ip:192.0.2.1");
+        assert_eq!(layer.label("ip:192.0.2.2", now), "ip:192.0.2.2");
+        assert_eq!(layer.label("ip:192.0.2.3", now), OTHER);
+        assert_eq!(layer.label("ip:192.0.2.1", now), "ip:192.0.2.1");
+        assert_eq!(layer.label(ANONYMOUS, now), ANONYMOUS);
+
+        // Clients arriving later are labeled in the next window
+        let later = now + Duration::from_secs(60);
+        assert_eq!(layer.label("ip:192.0.2.3", later), "ip:192.0.2.3");
+    }
+}
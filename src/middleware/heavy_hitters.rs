@@ -0,0 +1,528 @@
+//! Middleware for tracking the heaviest clients and JSON-RPC methods.
+//!
+//! Clients can't be metric labels without exploding cardinality, so each client and method is fed
+//! into a Space-Saving sketch which keeps the approximate top entries in bounded memory. Sketches
+//! cover a tumbling window: the top `n` entries of the last completed window are exported as
+//! gauges, and [`HeavyHittersLayer::router`] serves both the last and the current window as JSON
+//! so that a noisy client can be found during an incident.
+//!
+//! Keys are spread by hash over independently locked shards so that concurrent requests rarely
+//! contend, the top entries are merged across shards when read.
+//!
+//! Clients are read from the [`ClientIdentity`] extension, requests without one are `anonymous`.
+
+use crate::middleware::{ClientIdentity, is_json_rpc, read_call};
+use axum::{Json, Router, body::Body, http::Request, response::Response, routing::get};
+use futures_util::future::BoxFuture;
+use opentelemetry::{KeyValue, global, metrics::Meter};
+use serde_json::{Value, json};
+use std::{
+    collections::{BTreeSet, HashMap},
+    convert::Infallible,
+    hash::{BuildHasher, RandomState},
+    mem,
+    sync::{Arc, Mutex},
+    task::{Context, Poll},
+    time::{Duration, Instant},
+};
+use tower::{Layer, Service};
+
+const DEFAULT_TOP: usize = 10;
+const DEFAULT_WINDOW: Duration = Duration::from_secs(60);
+/// Entries tracked per requested top entry, extra slots keep the error of the top entries low
+const CAPACITY_FACTOR: usize = 10;
+/// Independently locked sketches per dimension
+const SHARDS: usize = 8;
+
+#[derive(Clone, Copy)]
+enum Measure {
+    Requests,
+    Bytes,
+    /// Cumulative latency, tracked in microseconds
+    Latency,
+}
+
+impl Measure {
+    const ALL: [Self; 3] = [Self::Requests, Self::Bytes, Self::Latency];
+
+    fn name(self) -> &'static str {
+        match self {
+            Self::Requests => "requests",
+            Self::Bytes => "bytes",
+            Self::Latency => "latency_seconds",
+        }
+    }
+
+    fn value(self, weight: u64) -> Value {
+        match self {
+            Self::Latency => json!(weight as f64 / 1e6),
+            _ => json!(weight),
+        }
+    }
+}
+
+#[derive(Clone, Copy)]
+enum Dimension {
+    Client,
+    Method,
+}
+
+impl Dimension {
+    fn name(self) -> &'static str {
+        match self {
+            Self::Client => "client",
+            Self::Method => "method",
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+struct Estimate {
+    count: u64,
+    /// Upper bound of the overestimation of `count`
+    error: u64,
+}
+
+/// Space-Saving sketch keeping at most `capacity` entries.
+///
+/// Counts of entries in the sketch are overestimated by at most `error`, and any entry heavier than
+/// `total / capacity` is guaranteed to be tracked. Entries are also indexed by count so that the
+/// lightest one is evicted in `O(log capacity)`.
+#[derive(Clone, Debug)]
+struct SpaceSaving {
+    capacity: usize,
+    entries: HashMap<Arc<str>, Estimate>,
+    by_count: BTreeSet<(u64, Arc<str>)>,
+}
+
+impl SpaceSaving {
+    fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            entries: HashMap::with_capacity(capacity),
+            by_count: BTreeSet::new(),
+        }
+    }
+
+    fn offer(&mut self, key: &str, weight: u64) {
+        if let Some((shared, estimate)) = self.entries.get_key_value(key) {
+            let (shared, mut estimate) = (shared.clone(), *estimate);
+            self.by_count.remove(&(estimate.count, shared.clone()));
+            estimate.count += weight;
+            self.by_count.insert((estimate.count, shared.clone()));
+            self.entries.insert(shared, estimate);
+            return;
+        }
+
+        // Evict the lightest entry once full, the newcomer inherits its count as error
+        let error = if self.entries.len() < self.capacity {
+            0
+        } else {
+            let Some((min, evicted)) = self.by_count.pop_first() else {
+                return;
+            };
+            self.entries.remove(&evicted);
+            min
+        };
+
+        let key: Arc<str> = key.into();
+        let count = error + weight;
+        self.by_count.insert((count, key.clone()));
+        self.entries.insert(key, Estimate { count, error });
+    }
+
+    fn top(&self, n: usize) -> Vec<(&str, Estimate)> {
+        let mut entries: Vec<_> = self
+            .entries
+            .iter()
+            .map(|(key, estimate)| (&**key, *estimate))
+            .collect();
+        entries.sort_unstable_by(|a, b| b.1.count.cmp(&a.1.count).then(a.0.cmp(b.0)));
+        entries.truncate(n);
+        entries
+    }
+}
+
+/// One sketch per measure.
+fn sketches(capacity: usize) -> [SpaceSaving; 3] {
+    std::array::from_fn(|_| SpaceSaving::new(capacity))
+}
+
+/// Sketches of one dimension and shard for the current and the last completed window.
+struct Window {
+    epoch: u64,
+    current: [SpaceSaving; 3],
+    previous: [SpaceSaving; 3],
+}
+
+impl Window {
+    fn new(capacity: usize) -> Self {
+        Self {
+            epoch: 0,
+            current: sketches(capacity),
+            previous: sketches(capacity),
+        }
+    }
+
+    fn rotate(&mut self, epoch: u64, capacity: usize) {
+        if epoch <= self.epoch {
+            return;
+        }
+
+        let current = mem::replace(&mut self.current, sketches(capacity));
+        // The current window is stale when nothing was recorded for a whole window
+        self.previous = if epoch == self.epoch + 1 {
+            current
+        } else {
+            sketches(capacity)
+        };
+        self.epoch = epoch;
+    }
+}
+
+struct Tracker {
+    top: usize,
+    window: Duration,
+    /// Capacity of the sketches of each shard
+    capacity: usize,
+    /// Start of the first window, windows of every shard are aligned to it
+    origin: Instant,
+    hasher: RandomState,
+    /// Shards per dimension, keys are assigned by hash so that concurrent requests rarely contend
+    shards: [Vec<Mutex<Window>>; 2],
+}
+
+impl Tracker {
+    fn new(top: usize, window: Duration) -> Self {
+        let top = top.max(1);
+        let capacity = (top * CAPACITY_FACTOR).div_ceil(SHARDS).max(top);
+        Self {
+            top,
+            window,
+            capacity,
+            origin: Instant::now(),
+            hasher: RandomState::new(),
+            shards: std::array::from_fn(|_| {
+                (0..SHARDS)
+                    .map(|_| Mutex::new(Window::new(capacity)))
+                    .collect()
+            }),
+        }
+    }
+
+    fn epoch(&self, now: Instant) -> u64 {
+        let window = self.window.as_nanos().max(1);
+        (now.duration_since(self.origin).as_nanos() / window) as u64
+    }
+
+    fn shard(&self, dimension: Dimension, key: &str) -> &Mutex<Window> {
+        let shards = &self.shards[dimension as usize];
+        &shards[self.hasher.hash_one(key) as usize % shards.len()]
+    }
+
+    fn record(&self, client: &str, method: &str, bytes: u64, latency: Duration) {
+        let now = Instant::now();
+        self.offer(now, Dimension::Client, client, bytes, latency);
+        self.offer(now, Dimension::Method, method, bytes, latency);
+    }
+
+    fn offer(&self, now: Instant, dimension: Dimension, key: &str, bytes: u64, latency: Duration) {
+        let epoch = self.epoch(now);
+        let Ok(mut window) = self.shard(dimension, key).lock() else {
+            return;
+        };
+        window.rotate(epoch, self.capacity);
+
+        let [requests, size, elapsed] = &mut window.current;
+        requests.offer(key, 1);
+        size.offer(key, bytes);
+        elapsed.offer(key, latency.as_micros() as u64);
+    }
+
+    /// Top entries across the shards of the current or the last completed window.
+    fn top(
+        &self,
+        now: Instant,
+        dimension: Dimension,
+        measure: Measure,
+        previous: bool,
+    ) -> Vec<(String, Estimate)> {
+        let epoch = self.epoch(now);
+        let mut top = Vec::new();
+
+        for shard in &self.shards[dimension as usize] {
+            let Ok(mut window) = shard.lock() else {
+                continue;
+            };
+            window.rotate(epoch, self.capacity);
+
+            let sketches = if previous {
+                &window.previous
+            } else {
+                &window.current
+            };
+            let entries = sketches[measure as usize].top(self.top).into_iter();
+            top.extend(entries.map(|(key, estimate)| (key.to_string(), estimate)));
+        }
+
+        top.sort_unstable_by(|a, b| b.1.count.cmp(&a.1.count).then(a.0.cmp(&b.0)));
+        top.truncate(self.top);
+        top
+    }
+
+    /// Top entries of the last completed window.
+    fn previous(&self, dimension: Dimension, measure: Measure) -> Vec<(String, Estimate)> {
+        self.top(Instant::now(), dimension, measure, true)
+    }
+
+    fn report(&self) -> Value {
+        let now = Instant::now();
+
+        let report = |previous: bool| {
+            let mut report = serde_json::Map::new();
+            for dimension in [Dimension::Client, Dimension::Method] {
+                let mut measures = serde_json::Map::new();
+                for measure in Measure::ALL {
+                    let top = self.top(now, dimension, measure, previous);
+                    let top = top.into_iter().map(|(key, estimate)| {
+                        json!({
+                            dimension.name(): key,
+                            "value": measure.value(estimate.count),
+                            "error": measure.value(estimate.error),
+                        })
+                    });
+                    measures.insert(measure.name().to_string(), top.collect());
+                }
+                report.insert(format!("{}s", dimension.name()), measures.into());
+            }
+            Value::Object(report)
+        };
+
+        let elapsed = now.duration_since(self.origin).as_secs_f64();
+        json!({
+            "window_seconds": self.window.as_secs_f64(),
+            "current_window_elapsed_seconds": elapsed % self.window.as_secs_f64(),
+            "current": report(false),
+            "previous": report(true),
+        })
+    }
+}
+
+#[derive(Clone)]
+pub struct HeavyHittersLayer {
+    tracker: Arc<Tracker>,
+}
+
+impl HeavyHittersLayer {
+    pub fn builder() -> HeavyHittersLayerBuilder {
+        HeavyHittersLayerBuilder {
+            top: DEFAULT_TOP,
+            window: DEFAULT_WINDOW,
+        }
+    }
+
+    /// Router serving the top entries of the current and last window on `GET /heavy-hitters`.
+    ///
+    /// Meant for an internal admin listener, it exposes client identities.
+    pub fn router(&self) -> Router {
+        let tracker = self.tracker.clone();
+        Router::new().route(
+            "/heavy-hitters",
+            get(move || async move { Json(tracker.report()) }),
+        )
+    }
+}
+
+impl Default for HeavyHittersLayer {
+    fn default() -> Self {
+        Self::builder().build()
+    }
+}
+
+pub struct HeavyHittersLayerBuilder {
+    top: usize,
+    window: Duration,
+}
+
+impl HeavyHittersLayerBuilder {
+    /// Number of entries exported per dimension and measure, defaults to 10.
+    pub fn top(mut self, top: usize) -> Self {
+        self.top = top;
+        self
+    }
+
+    /// Length of the tumbling window, defaults to a minute.
+    pub fn window(mut self, window: Duration) -> Self {
+        self.window = window;
+        self
+    }
+
+    pub fn build(self) -> HeavyHittersLayer {
+        let tracker = Arc::new(Tracker::new(self.top, self.window));
+
+        let meter = global::meter("jsonrpc");
+        for dimension in [Dimension::Client, Dimension::Method] {
+            register_gauges(&meter, &tracker, dimension);
+        }
+
+        HeavyHittersLayer { tracker }
+    }
+}
+
+/// Registers `jsonrpc_top_{client,method}_{requests,bytes,latency}` reporting the last window.
+fn register_gauges(meter: &Meter, tracker: &Arc<Tracker>, dimension: Dimension) {
+    let name = dimension.name();
+
+    let requests = tracker.clone();
+    meter
+        .u64_observable_gauge(format!("jsonrpc_top_{name}_requests"))
+        .with_unit("{request}")
+        .with_callback(move |observer| {
+            for (key, estimate) in requests.previous(dimension, Measure::Requests) {
+                observer.observe(estimate.count, &[KeyValue::new(name, key)]);
+            }
+        })
+        .build();
+
+    let bytes = tracker.clone();
+    meter
+        .u64_observable_gauge(format!("jsonrpc_top_{name}_bytes"))
+        .with_unit("By")
+        .with_callback(move |observer| {
+            for (key, estimate) in bytes.previous(dimension, Measure::Bytes) {
+                observer.observe(estimate.count, &[KeyValue::new(name, key)]);
+            }
+        })
+        .build();
+
+    let latency = tracker.clone();
+    meter
+        .f64_observable_gauge(format!("jsonrpc_top_{name}_latency"))
+        .with_unit("s")
+        .with_callback(move |observer| {
+            for (key, estimate) in latency.previous(dimension, Measure::Latency) {
+                observer.observe(estimate.count as f64 / 1e6, &[KeyValue::new(name, key)]);
+            }
+        })
+        .build();
+}
+
+impl<S> Layer<S> for HeavyHittersLayer {
+    type Service = HeavyHitters<S>;
+    fn layer(&self, inner: S) -> Self::Service {
+        HeavyHitters {
+            inner,
+            tracker: self.tracker.clone(),
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct HeavyHitters<S> {
+    inner: S,
+    tracker: Arc<Tracker>,
+}
+
+impl<S> Service<Request<Body>> for HeavyHitters<S>
+where
+    S: Service<Request<Body>, Response = Response, Error = Infallible> + Clone + Send + 'static,
+    S::Future: Send + 'static,
+{
+    type Response = S::Response;
+    type Error = Infallible;
+    type Future = BoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, request: Request<Body>) -> Self::Future {
+        let mut inner = self.inner.clone();
+        if !is_json_rpc(&request) {
+            return Box::pin(async move { inner.call(request).await });
+        }
+        let tracker = self.tracker.clone();
+
+        Box::pin(async move {
+            let start = Instant::now();
+            let (request, call) = match read_call(request, "HeavyHitters").await {
+                Ok(read) => read,
+                Err(response) => return Ok(response),
+            };
+
+            let client = request
+                .extensions()
+                .get::<ClientIdentity>()
+                .map_or("anonymous", ClientIdentity::id)
+                .to_string();
+
+            let response = inner.call(request).await;
+            let bytes_size = call.size.unwrap_or_default() as u64;
+            tracker.record(&client, &call.method, bytes_size, start.elapsed());
+
+            response
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_space_saving_keeps_heavy_hitters() {
+        let mut sketch = SpaceSaving::new(3);
+        for i in 0..100 {
+            sketch.offer("heavy", 10);
+            sketch.offer(&format!("light-{i}"), 1);
+        }
+
+        let top = sketch.top(1);
+        assert_eq!(top[0].0, "heavy");
+        assert_eq!(top[0].1.count - top[0].1.error, 1000);
+        assert_eq!(sketch.entries.len(), 3);
+    }
+
+    #[test]
+    fn test_window_rotation() {
+        let tracker = Tracker::new(10, Duration::from_secs(60));
+        let started = tracker.origin;
+        let top = |now, dimension, measure, previous| {
+            tracker
+                .top(now, dimension, measure, previous)
+                .into_iter()
+                .map(|(key, estimate)| (key, estimate.count))
+                .collect::<Vec<_>>()
+        };
+
+        for (client, bytes) in [("ip:192.0.2.1", 128), ("ip:192.0.2.2", 512)] {
+            tracker.offer(
+                started,
+                Dimension::Client,
+                client,
+                bytes,
+                Duration::from_millis(5),
+            );
+        }
+        tracker.offer(
+            started,
+            Dimension::Method,
+            "eth_call",
+            128,
+            Duration::from_millis(5),
+        );
+
+        let now = started + Duration::from_secs(61);
+        assert_eq!(
+            top(now, Dimension::Client, Measure::Bytes, true),
+            vec![
+                ("ip:192.0.2.2".to_string(), 512),
+                ("ip:192.0.2.1".to_string(), 128)
+            ]
+        );
+        assert!(top(now, Dimension::Client, Measure::Bytes, false).is_empty());
+
+        let now = started + Duration::from_secs(200);
+        assert!(top(now, Dimension::Method, Measure::Requests, true).is_empty());
+    }
+}
@@ -1,34 +1,171 @@
 //! Middleware for recording JSON-RPC method body size and latency
+//!
+//! Streamed responses also record their time to first and last byte, and requests cancelled
+//! before their response are counted in `jsonrpc_method_cancelled`.
 
-use crate::middleware::create_response;
+use crate::middleware::{
+    BodyObserver, JsonRpcCall,
+    client::client_attribute,
+    is_json_rpc,
+    latency_sample::{LatencySampleFilter, LatencySampler},
+    observe_response, read_call,
+};
 use axum::{
-    body::{Body, to_bytes},
+    body::{Body, Bytes},
     http::Request,
     response::Response,
 };
 use futures_util::future::BoxFuture;
-use opentelemetry::{KeyValue, global, metrics::Histogram};
-use rpc::Request as RpcRequest;
+use opentelemetry::{
+    KeyValue, global,
+    metrics::{Counter, Histogram, Meter},
+};
 use std::{
     convert::Infallible,
     task::{Context, Poll},
-    time::Instant,
+    time::{Duration, Instant},
 };
 use tower::{Layer, Service};
-use tracing::warn;
+use tracing::{Span, info};
+use tracing_opentelemetry::OpenTelemetrySpanExt;
+
+/// Latency buckets in seconds, resolving cache hits below a millisecond up to slow `eth_getLogs`
+pub(crate) const DEFAULT_LATENCY_BUCKETS: &[f64] = &[
+    0.0001, 0.00025, 0.0005, 0.001, 0.0025, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5,
+    5.0, 10.0, 30.0,
+];
+
+/// Body size buckets in bytes
+const DEFAULT_SIZE_BUCKETS: &[f64] = &[
+    64.0, 256.0, 1024.0, 4096.0, 16384.0, 65536.0, 262144.0, 1048576.0, 4194304.0,
+];
+
+/// Method label of requests cancelled before their body is parsed
+const UNKNOWN_METHOD: &str = "unknown";
+
+/// Minimum time between two latency samples of the same method and bucket
+const SAMPLE_INTERVAL: Duration = Duration::from_secs(10);
 
 #[derive(Clone)]
 pub struct JsonRpcMethodHistogramLayer {
     size: Histogram<u64>,
-    latency: Histogram<u64>,
+    latency: Histogram<f64>,
+    /// Deprecated `jsonrpc_method_latency_ms`, removed in the next release
+    legacy_latency: Histogram<u64>,
+    first_byte: Histogram<f64>,
+    last_byte: Histogram<f64>,
+    cancelled: Counter<u64>,
+    cancelled_latency: Histogram<f64>,
+    latency_samples: LatencySampler,
+    client: bool,
+}
+
+impl JsonRpcMethodHistogramLayer {
+    pub fn builder() -> JsonRpcMethodHistogramLayerBuilder {
+        JsonRpcMethodHistogramLayerBuilder {
+            size_buckets: DEFAULT_SIZE_BUCKETS.to_vec(),
+            latency_buckets: DEFAULT_LATENCY_BUCKETS.to_vec(),
+            latency_sample_filter: LatencySampleFilter::default(),
+            client: false,
+        }
+    }
 }
 
 impl Default for JsonRpcMethodHistogramLayer {
     fn default() -> Self {
-        let meter = global::meter("jsonrpc");
-        let size = meter.u64_histogram("jsonrpc_method_body_size").build();
-        let latency = meter.u64_histogram("jsonrpc_method_latency_ms").build();
-        Self { size, latency }
+        Self::builder().build()
+    }
+}
+
+/// Configures the explicit bucket boundaries and latency sample logging of the histograms.
+///
+/// The boundaries are only a hint to the SDK, views registered on the meter provider (for example
+/// exponential histograms) take precedence.
+pub struct JsonRpcMethodHistogramLayerBuilder {
+    size_buckets: Vec<f64>,
+    latency_buckets: Vec<f64>,
+    latency_sample_filter: LatencySampleFilter,
+    client: bool,
+}
+
+impl JsonRpcMethodHistogramLayerBuilder {
+    /// Boundaries of `jsonrpc_method_body_size` in bytes.
+    pub fn size_buckets(mut self, buckets: Vec<f64>) -> Self {
+        self.size_buckets = buckets;
+        self
+    }
+
+    /// Boundaries of `jsonrpc_method_latency` and the time to first and last byte in seconds.
+    pub fn latency_buckets(mut self, buckets: Vec<f64>) -> Self {
+        self.latency_buckets = buckets;
+        self
+    }
+
+    /// Selects the latency measurements logged as samples linked to their trace, defaults to none.
+    /// See [`LatencySampleFilter`], these are log events and not exemplars.
+    pub fn latency_sample_filter(mut self, filter: LatencySampleFilter) -> Self {
+        self.latency_sample_filter = filter;
+        self
+    }
+
+    /// Adds the `client` attribute from the [`ClientIdentity`](super::ClientIdentity) extension.
+    pub fn client_attribute(mut self) -> Self {
+        self.client = true;
+        self
+    }
+
+    pub fn build(self) -> JsonRpcMethodHistogramLayer {
+        self.build_with_meter(&global::meter("jsonrpc"))
+    }
+
+    /// Creates the instruments from `meter` rather than the global `jsonrpc` meter.
+    pub(crate) fn build_with_meter(self, meter: &Meter) -> JsonRpcMethodHistogramLayer {
+        let latency_samples = LatencySampler::new(
+            self.latency_sample_filter,
+            &self.latency_buckets,
+            SAMPLE_INTERVAL,
+        );
+
+        let size = meter
+            .u64_histogram("jsonrpc_method_body_size")
+            .with_unit("By")
+            .with_boundaries(self.size_buckets)
+            .build();
+        let latency = meter
+            .f64_histogram("jsonrpc_method_latency")
+            .with_unit("s")
+            .with_boundaries(self.latency_buckets.clone())
+            .build();
+        // Unchanged from previous releases so that existing queries keep matching
+        let legacy_latency = meter.u64_histogram("jsonrpc_method_latency_ms").build();
+        let first_byte = meter
+            .f64_histogram("jsonrpc_method_time_to_first_byte")
+            .with_unit("s")
+            .with_boundaries(self.latency_buckets.clone())
+            .build();
+        let last_byte = meter
+            .f64_histogram("jsonrpc_method_time_to_last_byte")
+            .with_unit("s")
+            .with_boundaries(self.latency_buckets.clone())
+            .build();
+        let cancelled = meter.u64_counter("jsonrpc_method_cancelled").build();
+        // Kept apart from `jsonrpc_method_latency` so that latency objectives exclude cancellations
+        let cancelled_latency = meter
+            .f64_histogram("jsonrpc_method_cancelled_latency")
+            .with_unit("s")
+            .with_boundaries(self.latency_buckets)
+            .build();
+        JsonRpcMethodHistogramLayer {
+            size,
+            latency,
+            legacy_latency,
+            first_byte,
+            last_byte,
+            cancelled,
+            cancelled_latency,
+            latency_samples,
+            client: self.client,
+        }
     }
 }
 
@@ -39,6 +176,13 @@ impl<S> Layer<S> for JsonRpcMethodHistogramLayer {
             inner,
             size: self.size.clone(),
             latency: self.latency.clone(),
+            legacy_latency: self.legacy_latency.clone(),
+            first_byte: self.first_byte.clone(),
+            last_byte: self.last_byte.clone(),
+            cancelled: self.cancelled.clone(),
+            cancelled_latency: self.cancelled_latency.clone(),
+            latency_samples: self.latency_samples.clone(),
+            client: self.client,
         }
     }
 }
@@ -47,7 +191,15 @@ impl<S> Layer<S> for JsonRpcMethodHistogramLayer {
 pub struct JsonRpcMethodHistogram<S> {
     inner: S,
     size: Histogram<u64>,
-    latency: Histogram<u64>,
+    latency: Histogram<f64>,
+    /// Deprecated `jsonrpc_method_latency_ms`, removed in the next release
+    legacy_latency: Histogram<u64>,
+    first_byte: Histogram<f64>,
+    last_byte: Histogram<f64>,
+    cancelled: Counter<u64>,
+    cancelled_latency: Histogram<f64>,
+    latency_samples: LatencySampler,
+    client: bool,
 }
 
 impl<S> Service<Request<Body>> for JsonRpcMethodHistogram<S>
@@ -65,57 +217,314 @@ where
 
     fn call(&mut self, request: Request<Body>) -> Self::Future {
         let mut inner = self.inner.clone();
+        if !is_json_rpc(&request) {
+            return Box::pin(async move { inner.call(request).await });
+        }
         let size = self.size.clone();
         let latency = self.latency.clone();
+        let legacy_latency = self.legacy_latency.clone();
+        let first_byte = self.first_byte.clone();
+        let last_byte = self.last_byte.clone();
+        let cancelled = self.cancelled.clone();
+        let cancelled_latency = self.cancelled_latency.clone();
+        let latency_samples = self.latency_samples.clone();
+        let client = self.client;
 
         Box::pin(async move {
             let start = Instant::now();
-            let (parts, body) = request.into_parts();
-
-            let (request, method) = if let Some(json_rpc) = parts.extensions.get::<RpcRequest>() {
-                (
-                    {
-                        if let Some(bytes_size) = parts.extensions.get::<usize>() {
-                            size.record(
-                                *bytes_size as u64,
-                                &[KeyValue::new("method", json_rpc.method.to_lowercase())],
-                            );
-                        }
-                        Request::from_parts(parts.clone(), body)
-                    },
-                    Some(json_rpc.method.to_lowercase()),
-                )
-            } else {
-                let bytes = match to_bytes(body, usize::MAX).await {
-                    Ok(bytes) => bytes,
-                    Err(error) => {
-                        warn!(%error, middleware = "JsonRpcMethodMetrics", "Failed to read request body");
-                        return Ok(create_response("Failed to read request body"));
-                    }
-                };
-
-                let method = if let Ok(json_rpc) = serde_json::from_slice::<RpcRequest>(&bytes) {
-                    size.record(
-                        bytes.len() as u64,
-                        &[KeyValue::new("method", json_rpc.method.to_lowercase())],
-                    );
-
-                    Some(json_rpc.method.to_lowercase())
-                } else {
-                    None
-                };
-
-                (Request::from_parts(parts, Body::from(bytes)), method)
+            // Armed before the body is read, requests can be cancelled while it is received
+            let mut guard = CancelGuard(Some(Cancellation {
+                cancelled,
+                latency: cancelled_latency,
+                method: UNKNOWN_METHOD.to_string(),
+                attributes: vec![KeyValue::new("method", UNKNOWN_METHOD)],
+                start,
+                span: Span::current(),
+            }));
+            let (
+                request,
+                JsonRpcCall {
+                    method,
+                    size: bytes_size,
+                },
+            ) = match read_call(request, "JsonRpcMethodMetrics").await {
+                Ok(read) => read,
+                Err(response) => {
+                    guard.0 = None;
+                    return Ok(response);
+                }
             };
+            let client = client.then(|| client_attribute(request.extensions()));
 
-            let response = inner.call(request).await;
-            let elapsed_ms = start.elapsed().as_millis() as u64;
+            let mut attributes = vec![KeyValue::new("method", method.clone())];
+            attributes.extend(client);
 
-            if let Some(method) = method {
-                latency.record(elapsed_ms, &[KeyValue::new("method", method)]);
+            if let Some(bytes_size) = bytes_size {
+                size.record(bytes_size as u64, &attributes);
             }
 
-            response
+            if let Some(cancellation) = guard.0.as_mut() {
+                cancellation.method.clone_from(&method);
+                cancellation.attributes.clone_from(&attributes);
+            }
+            let response = inner.call(request).await?;
+            let Some(cancellation) = guard.0.take() else {
+                unreachable!("armed until the response is returned")
+            };
+            let elapsed = start.elapsed();
+            legacy_latency.record(elapsed.as_millis() as u64, &attributes);
+            let elapsed = elapsed.as_secs_f64();
+
+            latency_samples.offer(&method, elapsed);
+            latency.record(elapsed, &attributes);
+
+            Ok(observe_response(
+                response,
+                ByteTimer {
+                    cancellation,
+                    first_byte: Some(first_byte),
+                    last_byte,
+                },
+            ))
         })
     }
 }
+
+/// Request recorded as cancelled if it ends before the last byte of its response.
+struct Cancellation {
+    cancelled: Counter<u64>,
+    latency: Histogram<f64>,
+    /// [`UNKNOWN_METHOD`] until the request body is parsed
+    method: String,
+    attributes: Vec<KeyValue>,
+    start: Instant,
+    /// Captured while polled, the future and body aren't dropped within the request span
+    span: Span,
+}
+
+impl Cancellation {
+    fn record(&self) {
+        let elapsed = self.start.elapsed();
+        self.cancelled.add(1, &self.attributes);
+        self.latency.record(elapsed.as_secs_f64(), &self.attributes);
+        self.span.set_attribute("jsonrpc.cancelled", true);
+        info!(
+            parent: &self.span,
+            method = self.method,
+            elapsed_ms = elapsed.as_millis(),
+            "Request cancelled"
+        );
+    }
+}
+
+/// Records the request as cancelled when the future is dropped before the response.
+struct CancelGuard(Option<Cancellation>);
+
+impl Drop for CancelGuard {
+    fn drop(&mut self) {
+        if let Some(cancellation) = &self.0 {
+            cancellation.record();
+        }
+    }
+}
+
+/// Records the time to the first and last data frame of the response body, or the request as
+/// cancelled if the body is dropped before its end, typically when the client disconnects.
+struct ByteTimer {
+    cancellation: Cancellation,
+    /// Taken once recorded
+    first_byte: Option<Histogram<f64>>,
+    last_byte: Histogram<f64>,
+}
+
+impl ByteTimer {
+    fn elapsed(&self) -> f64 {
+        self.cancellation.start.elapsed().as_secs_f64()
+    }
+
+    fn record_first_byte(&mut self) {
+        if let Some(histogram) = self.first_byte.take() {
+            histogram.record(self.elapsed(), &self.cancellation.attributes);
+        }
+    }
+}
+
+impl BodyObserver for ByteTimer {
+    fn data(&mut self, _data: &Bytes) {
+        self.record_first_byte();
+    }
+
+    fn end(mut self, complete: bool) {
+        if !complete {
+            self.cancellation.record();
+            return;
+        }
+        self.record_first_byte();
+        self.last_byte
+            .record(self.elapsed(), &self.cancellation.attributes);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::middleware::ObservedBody;
+    use axum::body::HttpBody;
+    use futures_util::{StreamExt, stream};
+    use opentelemetry::{Value, metrics::MeterProvider, trace::TracerProvider};
+    use opentelemetry_sdk::{
+        metrics::{
+            InMemoryMetricExporter, PeriodicReader, SdkMeterProvider,
+            data::{AggregatedMetrics, MetricData},
+        },
+        trace::{InMemorySpanExporter, SdkTracerProvider},
+    };
+    use std::pin::Pin;
+    use tower::service_fn;
+    use tracing::{Instrument, info_span};
+    use tracing_subscriber::{layer::SubscriberExt, registry};
+
+    #[tokio::test]
+    async fn test_cancelled() {
+        let metric_exporter = InMemoryMetricExporter::default();
+        let meter_provider = SdkMeterProvider::builder()
+            .with_reader(PeriodicReader::builder(metric_exporter.clone()).build())
+            .build();
+        let span_exporter = InMemorySpanExporter::default();
+        let tracer_provider = SdkTracerProvider::builder()
+            .with_simple_exporter(span_exporter.clone())
+            .build();
+        let _guard = tracing::subscriber::set_default(
+            registry()
+                .with(tracing_opentelemetry::layer().with_tracer(tracer_provider.tracer("test"))),
+        );
+
+        let layer = JsonRpcMethodHistogramLayer::builder()
+            .build_with_meter(&meter_provider.meter("jsonrpc"));
+        let mut service = layer.layer(service_fn(|_request: Request<Body>| {
+            std::future::pending::<Result<Response, Infallible>>()
+        }));
+
+        let request = || {
+            Request::post("/")
+                .header("content-type", "application/json")
+                .body(Body::from(
+                    r#"{"jsonrpc":"2.0","method":"eth_call","params":[],"id":1}"#,
+                ))
+                .unwrap()
+        };
+        let mut future = service
+            .call(request())
+            .instrument(info_span!("http_request"));
+        assert!(futures_util::poll!(&mut future).is_pending());
+        drop(future);
+
+        // Disconnected while the response streams
+        let mut service = layer.layer(service_fn(|_request: Request<Body>| async {
+            let chunks = stream::once(async { Ok::<_, Infallible>(Bytes::from("[")) })
+                .chain(stream::pending());
+            Ok::<_, Infallible>(Response::new(Body::from_stream(chunks)))
+        }));
+        let response = service
+            .call(request())
+            .instrument(info_span!("http_request"))
+            .await
+            .unwrap();
+        let mut body = response.into_body();
+        std::future::poll_fn(|cx| Pin::new(&mut body).poll_frame(cx)).await;
+        drop(body);
+
+        meter_provider.force_flush().unwrap();
+        let metrics = metric_exporter.get_finished_metrics().unwrap();
+        let metric = |name: &str| {
+            metrics
+                .iter()
+                .flat_map(|metrics| metrics.scope_metrics())
+                .flat_map(|scope| scope.metrics())
+                .find(|metric| metric.name() == name)
+                .unwrap()
+        };
+
+        let AggregatedMetrics::U64(MetricData::Sum(cancelled)) =
+            metric("jsonrpc_method_cancelled").data()
+        else {
+            unreachable!()
+        };
+        let point = cancelled.data_points().next().unwrap();
+        assert_eq!(point.value(), 2);
+        assert!(
+            point
+                .attributes()
+                .any(|kv| kv.key.as_str() == "method" && kv.value.as_str() == "eth_call")
+        );
+
+        let histogram_count = |name: &str| {
+            let AggregatedMetrics::F64(MetricData::Histogram(histogram)) = metric(name).data()
+            else {
+                unreachable!()
+            };
+            histogram
+                .data_points()
+                .map(|point| point.count())
+                .sum::<u64>()
+        };
+        assert_eq!(histogram_count("jsonrpc_method_cancelled_latency"), 2);
+        // Only the streamed response returned its head
+        assert_eq!(histogram_count("jsonrpc_method_latency"), 1);
+        assert_eq!(histogram_count("jsonrpc_method_time_to_first_byte"), 1);
+        assert!(
+            metrics
+                .iter()
+                .flat_map(|metrics| metrics.scope_metrics())
+                .flat_map(|scope| scope.metrics())
+                .all(|metric| metric.name() != "jsonrpc_method_time_to_last_byte")
+        );
+
+        let spans = span_exporter.get_finished_spans().unwrap();
+        assert_eq!(spans.len(), 2);
+        assert!(spans.iter().all(|span| {
+            span.attributes
+                .iter()
+                .any(|kv| kv.key.as_str() == "jsonrpc.cancelled" && kv.value == Value::Bool(true))
+        }));
+    }
+
+    #[tokio::test]
+    async fn test_byte_timer() {
+        let layer = JsonRpcMethodHistogramLayer::default();
+        let timed = |body| {
+            ObservedBody::new(
+                body,
+                ByteTimer {
+                    cancellation: Cancellation {
+                        cancelled: layer.cancelled.clone(),
+                        latency: layer.cancelled_latency.clone(),
+                        method: "eth_call".to_string(),
+                        attributes: Vec::new(),
+                        start: Instant::now(),
+                        span: Span::none(),
+                    },
+                    first_byte: Some(layer.first_byte.clone()),
+                    last_byte: layer.last_byte.clone(),
+                },
+            )
+        };
+
+        let chunks = ["[", "{}", "]"].map(|chunk| Ok::<_, Infallible>(Bytes::from(chunk)));
+        let mut body = timed(Body::from_stream(stream::iter(chunks)));
+        assert!(body.observer.as_ref().unwrap().first_byte.is_some());
+
+        let frame = std::future::poll_fn(|cx| Pin::new(&mut body).poll_frame(cx)).await;
+        assert_eq!(frame.unwrap().unwrap().into_data().unwrap(), "[");
+        assert!(body.observer.as_ref().unwrap().first_byte.is_none());
+
+        while std::future::poll_fn(|cx| Pin::new(&mut body).poll_frame(cx))
+            .await
+            .is_some()
+        {}
+        assert!(body.observer.is_none());
+
+        let body = timed(Body::empty());
+        assert!(body.observer.is_none());
+    }
+}
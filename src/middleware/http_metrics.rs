@@ -0,0 +1,317 @@
+//! Middleware for recording HTTP server metrics following the OpenTelemetry semantic conventions
+//!
+//! Unlike the JSON-RPC layers it records every request, including health checks and websocket
+//! upgrades. Routes are labeled with their template from [`MatchedPath`], so the layer must be
+//! added with `Router::layer` for the route to be known. Requests are active and their duration
+//! runs until the last byte of the response body is sent.
+
+use crate::middleware::{BodyObserver, observe_response};
+use axum::{
+    body::{Body, Bytes, HttpBody},
+    extract::MatchedPath,
+    http::{Method, Request, header},
+    response::Response,
+};
+use futures_util::future::BoxFuture;
+use opentelemetry::{
+    KeyValue, global,
+    metrics::{Histogram, Meter, UpDownCounter},
+};
+use std::{
+    convert::Infallible,
+    task::{Context, Poll},
+    time::Instant,
+};
+use tower::{Layer, Service};
+
+/// Duration buckets in seconds recommended by the semantic conventions
+const DURATION_BUCKETS: &[f64] = &[
+    0.005, 0.01, 0.025, 0.05, 0.075, 0.1, 0.25, 0.5, 0.75, 1.0, 2.5, 5.0, 7.5, 10.0,
+];
+
+#[derive(Clone)]
+pub struct HttpMetricsLayer {
+    duration: Histogram<f64>,
+    active_requests: UpDownCounter<i64>,
+    request_size: Histogram<u64>,
+    response_size: Histogram<u64>,
+}
+
+impl Default for HttpMetricsLayer {
+    fn default() -> Self {
+        Self::with_meter(&global::meter("http"))
+    }
+}
+
+impl HttpMetricsLayer {
+    /// Creates the instruments from `meter` rather than the global `http` meter.
+    pub(crate) fn with_meter(meter: &Meter) -> Self {
+        let duration = meter
+            .f64_histogram("http.server.request.duration")
+            .with_unit("s")
+            .with_boundaries(DURATION_BUCKETS.to_vec())
+            .build();
+        let active_requests = meter
+            .i64_up_down_counter("http.server.active_requests")
+            .with_unit("{request}")
+            .build();
+        let request_size = meter
+            .u64_histogram("http.server.request.body.size")
+            .with_unit("By")
+            .build();
+        let response_size = meter
+            .u64_histogram("http.server.response.body.size")
+            .with_unit("By")
+            .build();
+        Self {
+            duration,
+            active_requests,
+            request_size,
+            response_size,
+        }
+    }
+}
+
+impl<S> Layer<S> for HttpMetricsLayer {
+    type Service = HttpMetrics<S>;
+    fn layer(&self, inner: S) -> Self::Service {
+        HttpMetrics {
+            inner,
+            layer: self.clone(),
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct HttpMetrics<S> {
+    inner: S,
+    layer: HttpMetricsLayer,
+}
+
+impl<S> Service<Request<Body>> for HttpMetrics<S>
+where
+    S: Service<Request<Body>, Response = Response, Error = Infallible> + Clone + Send + 'static,
+    S::Future: Send + 'static,
+{
+    type Response = S::Response;
+    type Error = Infallible;
+    type Future = BoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, request: Request<Body>) -> Self::Future {
+        let mut inner = self.inner.clone();
+        let metrics = self.layer.clone();
+
+        Box::pin(async move {
+            let start = Instant::now();
+
+            let method = KeyValue::new("http.request.method", method_name(request.method()));
+            let scheme = KeyValue::new(
+                "url.scheme",
+                request.uri().scheme_str().unwrap_or("http").to_string(),
+            );
+            let route = request
+                .extensions()
+                .get::<MatchedPath>()
+                .map(|path| KeyValue::new("http.route", path.as_str().to_string()));
+            let request_size = body_size(request.headers(), request.body());
+
+            let active_attributes = [method.clone(), scheme.clone()];
+            metrics.active_requests.add(1, &active_attributes);
+            let active = ActiveRequestGuard {
+                active_requests: metrics.active_requests.clone(),
+                attributes: active_attributes,
+            };
+
+            let response = inner.call(request).await?;
+
+            let status = response.status();
+            let mut attributes = vec![
+                method,
+                scheme,
+                KeyValue::new("http.response.status_code", i64::from(status.as_u16())),
+            ];
+            attributes.extend(route);
+            if status.is_server_error() {
+                attributes.push(KeyValue::new("error.type", status.as_str().to_string()));
+            }
+
+            if let Some(size) = request_size {
+                metrics.request_size.record(size, &attributes);
+            }
+
+            Ok(observe_response(
+                response,
+                ResponseObserver {
+                    metrics,
+                    attributes,
+                    start,
+                    size: 0,
+                    _active: active,
+                },
+            ))
+        })
+    }
+}
+
+/// Records the duration and body size once the response body ends.
+struct ResponseObserver {
+    metrics: HttpMetricsLayer,
+    attributes: Vec<KeyValue>,
+    start: Instant,
+    size: u64,
+    /// The request stays active until the body ends
+    _active: ActiveRequestGuard,
+}
+
+impl BodyObserver for ResponseObserver {
+    fn data(&mut self, data: &Bytes) {
+        self.size += data.len() as u64;
+    }
+
+    fn end(self, complete: bool) {
+        self.metrics
+            .duration
+            .record(self.start.elapsed().as_secs_f64(), &self.attributes);
+        // Bodies cut short didn't send their size
+        if complete {
+            self.metrics
+                .response_size
+                .record(self.size, &self.attributes);
+        }
+    }
+}
+
+/// Decrements `http.server.active_requests` at the end of the response or on cancellation.
+struct ActiveRequestGuard {
+    active_requests: UpDownCounter<i64>,
+    attributes: [KeyValue; 2],
+}
+
+impl Drop for ActiveRequestGuard {
+    fn drop(&mut self) {
+        self.active_requests.add(-1, &self.attributes);
+    }
+}
+
+/// Known methods are reported as is, anything else as `_OTHER` to bound the cardinality.
+fn method_name(method: &Method) -> &'static str {
+    match *method {
+        Method::GET => "GET",
+        Method::POST => "POST",
+        Method::PUT => "PUT",
+        Method::DELETE => "DELETE",
+        Method::HEAD => "HEAD",
+        Method::OPTIONS => "OPTIONS",
+        Method::PATCH => "PATCH",
+        Method::CONNECT => "CONNECT",
+        Method::TRACE => "TRACE",
+        _ => "_OTHER",
+    }
+}
+
+/// Size from `Content-Length` or an exact size hint, streamed bodies of unknown size are skipped.
+pub(crate) fn body_size(headers: &header::HeaderMap, body: &Body) -> Option<u64> {
+    headers
+        .get(header::CONTENT_LENGTH)
+        .and_then(|length| length.to_str().ok()?.parse().ok())
+        .or_else(|| body.size_hint().exact())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::{Router, body::to_bytes, http::StatusCode, routing::post};
+    use opentelemetry::metrics::MeterProvider;
+    use opentelemetry_sdk::metrics::{
+        InMemoryMetricExporter, PeriodicReader, SdkMeterProvider,
+        data::{AggregatedMetrics, MetricData},
+    };
+    use tower::ServiceExt;
+
+    #[tokio::test]
+    async fn test_router() {
+        let exporter = InMemoryMetricExporter::default();
+        let provider = SdkMeterProvider::builder()
+            .with_reader(PeriodicReader::builder(exporter.clone()).build())
+            .build();
+
+        let router = Router::new()
+            .route("/rpc/:chain", post(|| async { "result" }))
+            .route(
+                "/fail",
+                post(|| async { (StatusCode::INTERNAL_SERVER_ERROR, "failure") }),
+            )
+            .layer(HttpMetricsLayer::with_meter(&provider.meter("http")));
+
+        for uri in ["/rpc/mainnet", "/fail"] {
+            let request = Request::post(uri).body(Body::empty()).unwrap();
+            let response = router.clone().oneshot(request).await.unwrap();
+            to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        }
+
+        provider.force_flush().unwrap();
+        let metrics = exporter.get_finished_metrics().unwrap();
+        let metric = |name: &str| {
+            metrics
+                .iter()
+                .flat_map(|metrics| metrics.scope_metrics())
+                .flat_map(|scope| scope.metrics())
+                .find(|metric| metric.name() == name)
+                .unwrap()
+                .data()
+        };
+        let attribute = |attributes: Vec<&KeyValue>, key: &str| {
+            attributes
+                .into_iter()
+                .find(|kv| kv.key.as_str() == key)
+                .map(|kv| kv.value.to_string())
+        };
+
+        let AggregatedMetrics::F64(MetricData::Histogram(duration)) =
+            metric("http.server.request.duration")
+        else {
+            unreachable!()
+        };
+        let mut points = duration
+            .data_points()
+            .map(|point| {
+                let attributes = point.attributes().collect::<Vec<_>>();
+                (
+                    attribute(attributes.clone(), "http.route"),
+                    attribute(attributes.clone(), "http.response.status_code"),
+                    attribute(attributes, "error.type"),
+                )
+            })
+            .collect::<Vec<_>>();
+        points.sort();
+        assert_eq!(
+            points,
+            [
+                (Some("/fail".into()), Some("500".into()), Some("500".into())),
+                (Some("/rpc/:chain".into()), Some("200".into()), None),
+            ]
+        );
+
+        let AggregatedMetrics::U64(MetricData::Histogram(size)) =
+            metric("http.server.response.body.size")
+        else {
+            unreachable!()
+        };
+        let mut sizes = size
+            .data_points()
+            .map(|point| point.sum())
+            .collect::<Vec<_>>();
+        sizes.sort();
+        assert_eq!(sizes, [6, 7]);
+
+        let AggregatedMetrics::I64(MetricData::Sum(active)) = metric("http.server.active_requests")
+        else {
+            unreachable!()
+        };
+        assert!(active.data_points().all(|point| point.value() == 0));
+    }
+}
@@ -0,0 +1,98 @@
+//! Middleware for tracking the number of in-flight JSON-RPC method calls
+
+use crate::middleware::{is_json_rpc, read_call};
+use axum::{body::Body, http::Request, response::Response};
+use futures_util::future::BoxFuture;
+use opentelemetry::{KeyValue, global, metrics::UpDownCounter};
+use std::{
+    convert::Infallible,
+    task::{Context, Poll},
+};
+use tower::{Layer, Service};
+
+#[derive(Clone)]
+pub struct JsonRpcMethodInFlightLayer {
+    in_flight: UpDownCounter<i64>,
+}
+
+impl Default for JsonRpcMethodInFlightLayer {
+    fn default() -> Self {
+        let meter = global::meter("jsonrpc");
+        let in_flight = meter
+            .i64_up_down_counter("jsonrpc_method_in_flight")
+            .build();
+        Self { in_flight }
+    }
+}
+
+impl<S> Layer<S> for JsonRpcMethodInFlightLayer {
+    type Service = JsonRpcMethodInFlight<S>;
+    fn layer(&self, inner: S) -> Self::Service {
+        JsonRpcMethodInFlight {
+            inner,
+            in_flight: self.in_flight.clone(),
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct JsonRpcMethodInFlight<S> {
+    inner: S,
+    in_flight: UpDownCounter<i64>,
+}
+
+impl<S> Service<Request<Body>> for JsonRpcMethodInFlight<S>
+where
+    S: Service<Request<Body>, Response = Response, Error = Infallible> + Clone + Send + 'static,
+    S::Future: Send + 'static,
+{
+    type Response = S::Response;
+    type Error = Infallible;
+    type Future = BoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, request: Request<Body>) -> Self::Future {
+        let mut inner = self.inner.clone();
+        if !is_json_rpc(&request) {
+            return Box::pin(async move { inner.call(request).await });
+        }
+        let in_flight = self.in_flight.clone();
+
+        Box::pin(async move {
+            let (request, call) = match read_call(request, "JsonRpcMethodInFlight").await {
+                Ok(read) => read,
+                Err(response) => return Ok(response),
+            };
+
+            // Decremented when the call completes or the future is dropped on cancellation
+            let _guard = InFlightGuard::new(in_flight, call.method);
+
+            inner.call(request).await
+        })
+    }
+}
+
+struct InFlightGuard {
+    in_flight: UpDownCounter<i64>,
+    attributes: [KeyValue; 1],
+}
+
+impl InFlightGuard {
+    fn new(in_flight: UpDownCounter<i64>, method: String) -> Self {
+        let attributes = [KeyValue::new("method", method)];
+        in_flight.add(1, &attributes);
+        Self {
+            in_flight,
+            attributes,
+        }
+    }
+}
+
+impl Drop for InFlightGuard {
+    fn drop(&mut self) {
+        self.in_flight.add(-1, &self.attributes);
+    }
+}
@@ -0,0 +1,133 @@
+//! Latency sample logging linking JSON-RPC latency measurements to traces.
+//!
+//! This is not exemplar support: the OpenTelemetry SDK does not attach exemplars to histogram data
+//! points yet. Instead a sample of measurements is logged as events under the
+//! `telemetry::latency_sample` target. They are recorded inside the request span, therefore the
+//! exported log record carries the trace and span id of the measurement and the `le` field names
+//! the histogram bucket it fell into. Sampling is off unless enabled on the histogram layer.
+
+use opentelemetry::trace::TraceContextExt;
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+use tracing::{Span, info};
+use tracing_opentelemetry::OpenTelemetrySpanExt;
+
+const TARGET: &str = "telemetry::latency_sample";
+
+/// Upper bound of tracked method and bucket pairs, reached only with garbage method names
+const MAX_ENTRIES: usize = 10_000;
+
+/// Which measurements are eligible to be logged as latency samples.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum LatencySampleFilter {
+    AlwaysOn,
+    /// Only measurements recorded within a sampled trace.
+    TraceBased,
+    /// Nothing is logged, samples add up to one event per method and bucket every interval.
+    #[default]
+    AlwaysOff,
+}
+
+/// Logs at most one sample per method and histogram bucket every `interval`.
+#[derive(Clone)]
+pub(crate) struct LatencySampler {
+    filter: LatencySampleFilter,
+    boundaries: Arc<[f64]>,
+    interval: Duration,
+    last_offered: Arc<Mutex<HashMap<(String, usize), Instant>>>,
+}
+
+impl LatencySampler {
+    pub(crate) fn new(filter: LatencySampleFilter, boundaries: &[f64], interval: Duration) -> Self {
+        Self {
+            filter,
+            boundaries: boundaries.into(),
+            interval,
+            last_offered: Arc::default(),
+        }
+    }
+
+    /// Offers a latency measurement in seconds recorded within the current span.
+    pub(crate) fn offer(&self, method: &str, value: f64) {
+        if self.filter == LatencySampleFilter::AlwaysOff {
+            return;
+        }
+
+        let context = Span::current().context();
+        let span = context.span();
+        let span_context = span.span_context();
+
+        if self.filter == LatencySampleFilter::TraceBased && !span_context.is_sampled() {
+            return;
+        }
+
+        let bucket = self
+            .boundaries
+            .iter()
+            .position(|boundary| value <= *boundary)
+            .unwrap_or(self.boundaries.len());
+
+        if !self.admit(method, bucket, Instant::now()) {
+            return;
+        }
+
+        let le = match self.boundaries.get(bucket) {
+            Some(boundary) => boundary.to_string(),
+            None => "+Inf".to_string(),
+        };
+
+        info!(
+            target: TARGET,
+            method,
+            latency = value,
+            le,
+            trace_id = %span_context.trace_id(),
+            span_id = %span_context.span_id(),
+            "Latency sample"
+        );
+    }
+
+    fn admit(&self, method: &str, bucket: usize, now: Instant) -> bool {
+        let Ok(mut last_offered) = self.last_offered.lock() else {
+            return false;
+        };
+
+        let key = (method.to_string(), bucket);
+        if last_offered
+            .get(&key)
+            .is_some_and(|last| now.duration_since(*last) < self.interval)
+        {
+            return false;
+        }
+
+        if last_offered.len() >= MAX_ENTRIES {
+            last_offered.clear();
+        }
+        last_offered.insert(key, now);
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_admit_once_per_bucket_and_interval() {
+        let sampler = LatencySampler::new(
+            LatencySampleFilter::AlwaysOn,
+            &[0.1, 1.0],
+            Duration::from_secs(10),
+        );
+        let now = Instant::now();
+
+        assert!(sampler.admit("eth_call", 0, now));
+        assert!(!sampler.admit("eth_call", 0, now + Duration::from_secs(1)));
+        assert!(sampler.admit("eth_call", 1, now + Duration::from_secs(1)));
+        assert!(sampler.admit("eth_getlogs", 0, now + Duration::from_secs(1)));
+        assert!(sampler.admit("eth_call", 0, now + Duration::from_secs(10)));
+    }
+}
@@ -1,31 +1,37 @@
 //! Middleware for counting the number of JSON-RPC method calls
 
-use crate::middleware::create_response;
-use axum::{
-    body::{Body, to_bytes},
-    http::Request,
-    response::Response,
-};
+use crate::middleware::{client::client_attribute, is_json_rpc, read_call};
+use axum::{body::Body, http::Request, response::Response};
 use futures_util::future::BoxFuture;
 use opentelemetry::{KeyValue, global, metrics::Counter};
-use rpc::Request as RpcRequest;
 use std::{
     convert::Infallible,
     task::{Context, Poll},
 };
 use tower::{Layer, Service};
-use tracing::warn;
 
 #[derive(Clone)]
 pub struct JsonRpcMethodCounterLayer {
     counter: Counter<u64>,
+    client: bool,
+}
+
+impl JsonRpcMethodCounterLayer {
+    /// Adds the `client` attribute from the [`ClientIdentity`](super::ClientIdentity) extension.
+    pub fn client_attribute(mut self) -> Self {
+        self.client = true;
+        self
+    }
 }
 
 impl Default for JsonRpcMethodCounterLayer {
     fn default() -> Self {
         let meter = global::meter("jsonrpc");
         let counter = meter.u64_counter("jsonrpc_method_calls").build();
-        Self { counter }
+        Self {
+            counter,
+            client: false,
+        }
     }
 }
 
@@ -35,6 +41,7 @@ impl<S> Layer<S> for JsonRpcMethodCounterLayer {
         JsonRpcMethodCounter {
             inner,
             counter: self.counter.clone(),
+            client: self.client,
         }
     }
 }
@@ -43,6 +50,7 @@ impl<S> Layer<S> for JsonRpcMethodCounterLayer {
 pub struct JsonRpcMethodCounter<S> {
     inner: S,
     counter: Counter<u64>,
+    client: bool,
 }
 
 impl<S> Service<Request<Body>> for JsonRpcMethodCounter<S>
@@ -60,36 +68,22 @@ where
 
     fn call(&mut self, request: Request<Body>) -> Self::Future {
         let mut inner = self.inner.clone();
+        if !is_json_rpc(&request) {
+            return Box::pin(async move { inner.call(request).await });
+        }
         let counter = self.counter.clone();
+        let client = self.client;
 
         Box::pin(async move {
-            let (parts, body) = request.into_parts();
-
-            let request = if let Some(json_rpc) = parts.extensions.get::<RpcRequest>() {
-                counter.add(
-                    1,
-                    &[KeyValue::new("method", json_rpc.method.to_lowercase())],
-                );
-
-                Request::from_parts(parts, body)
-            } else {
-                let bytes = match to_bytes(body, usize::MAX).await {
-                    Ok(bytes) => bytes,
-                    Err(error) => {
-                        warn!(%error, middleware = "JsonRpcMethodCounter", "Failed to read request body");
-                        return Ok(create_response("Failed to read request body"));
-                    }
-                };
-
-                if let Ok(rpc_request) = serde_json::from_slice::<RpcRequest>(&bytes) {
-                    counter.add(
-                        1,
-                        &[KeyValue::new("method", rpc_request.method.to_lowercase())],
-                    );
-                }
-
-                Request::from_parts(parts, Body::from(bytes))
+            let (request, call) = match read_call(request, "JsonRpcMethodCounter").await {
+                Ok(read) => read,
+                Err(response) => return Ok(response),
             };
+            let client = client.then(|| client_attribute(request.extensions()));
+
+            let mut attributes = vec![KeyValue::new("method", call.method)];
+            attributes.extend(client);
+            counter.add(1, &attributes);
 
             inner.call(request).await
         })
@@ -1,19 +1,303 @@
+mod classify;
+mod client;
+mod heavy_hitters;
 mod histogram;
+mod http_metrics;
+mod in_flight;
+mod latency_sample;
 mod method_counter;
+mod request_id;
 mod request_validation;
+mod slo;
+mod slow_request;
+mod stack;
 mod tracing;
+mod websocket;
 
+use ::tracing::warn;
 use axum::{
-    body::Body,
-    http::{StatusCode, header},
+    body::{Body, Bytes, HttpBody, to_bytes},
+    http::{HeaderMap, HeaderName, Method, Request, StatusCode, header},
     response::Response,
 };
-pub use histogram::JsonRpcMethodHistogramLayer;
+pub use classify::{JsonRpcError, JsonRpcErrorLayer, JsonRpcFailureClass, ServerErrorClassifier};
+pub use client::{ClientIdSource, ClientIdentity, ClientIdentityLayer, ClientIdentityLayerBuilder};
+pub use heavy_hitters::{HeavyHittersLayer, HeavyHittersLayerBuilder};
+pub use histogram::{JsonRpcMethodHistogramLayer, JsonRpcMethodHistogramLayerBuilder};
+use http_body::{Frame, SizeHint};
+pub use http_metrics::HttpMetricsLayer;
+pub use in_flight::JsonRpcMethodInFlightLayer;
+pub use latency_sample::LatencySampleFilter;
 pub use method_counter::JsonRpcMethodCounterLayer;
+use opentelemetry::propagation::Extractor;
+pub use request_id::{
+    MAX_REQUEST_ID_LEN, MakeTelemetryRequestId, RequestIdFormat, RequestIdLayer,
+    RequestIdLayerBuilder, ValidateRequestId,
+};
 pub use request_validation::RequestValidationLayer;
-use rpc::{ErrorBody, Response as JsonRpcResponse, code::INVALID_REQUEST};
+use rpc::{ErrorBody, Request as RpcRequest, Response as JsonRpcResponse, code::INVALID_REQUEST};
+use serde::Deserialize;
 use serde_json::Value;
-pub use tracing::trace_layer;
+pub use slo::{SloLayer, SloLayerBuilder, SloObjective};
+pub use slow_request::{SlowRequestLayer, SlowRequestLayerBuilder};
+pub use stack::{JsonRpcTelemetryLayer, JsonRpcTelemetryLayerBuilder, JsonRpcTelemetryService};
+use std::{
+    pin::Pin,
+    task::{Context, Poll},
+};
+pub use tracing::{TraceLayerBuilder, trace_layer};
+pub use websocket::{PENDING_TIMEOUT, WebSocketSession, WebSocketTelemetry};
+
+/// Method label of request bodies that are valid JSON but not a JSON-RPC request
+pub(crate) const INVALID_METHOD: &str = "invalid";
+/// Method label of request bodies that are not valid JSON
+pub(crate) const UNPARSEABLE_METHOD: &str = "unparseable";
+
+/// Lowercased method of a JSON-RPC request body, or a dedicated label when the body can't be
+/// deserialized so that garbage and scanning traffic still shows up in the metrics.
+pub(crate) fn method_label(body: &[u8]) -> String {
+    match serde_json::from_slice::<RpcRequest>(body) {
+        Ok(json_rpc) => json_rpc.method.to_lowercase(),
+        Err(_) if serde_json::from_slice::<Value>(body).is_ok() => INVALID_METHOD.to_string(),
+        Err(_) => UNPARSEABLE_METHOD.to_string(),
+    }
+}
+
+/// Method label and body size of a JSON-RPC call, inserted as an extension by the first layer
+/// reading them so that the layers further in neither buffer nor parse the body again.
+#[derive(Clone, Debug)]
+pub(crate) struct JsonRpcCall {
+    pub(crate) method: String,
+    /// Body size in bytes, unknown when the request was parsed without it
+    pub(crate) size: Option<usize>,
+}
+
+/// Reads the [`JsonRpcCall`] of a request, only buffering and parsing the body when no outer layer
+/// did. Bodies that parse also get the [`RpcRequest`] and size extensions of
+/// [`RequestValidationLayer`].
+///
+/// A body that can't be read is answered with an error response, logged for `middleware`.
+pub(crate) async fn read_call(
+    request: Request<Body>,
+    middleware: &'static str,
+) -> Result<(Request<Body>, JsonRpcCall), Response> {
+    if let Some(call) = request.extensions().get::<JsonRpcCall>() {
+        let call = call.clone();
+        return Ok((request, call));
+    }
+
+    let (mut parts, body) = request.into_parts();
+    let (body, call) = if let Some(json_rpc) = parts.extensions.get::<RpcRequest>() {
+        let call = JsonRpcCall {
+            method: json_rpc.method.to_lowercase(),
+            size: parts.extensions.get::<usize>().copied(),
+        };
+        (body, call)
+    } else {
+        let bytes = match to_bytes(body, usize::MAX).await {
+            Ok(bytes) => bytes,
+            Err(error) => {
+                warn!(%error, middleware, "Failed to read request body");
+                return Err(create_response("Failed to read request body"));
+            }
+        };
+
+        let method = match serde_json::from_slice::<RpcRequest>(&bytes) {
+            Ok(json_rpc) => {
+                let method = json_rpc.method.to_lowercase();
+                parts.extensions.insert(json_rpc);
+                parts.extensions.insert(bytes.len());
+                method
+            }
+            Err(_) => method_label(&bytes),
+        };
+        let call = JsonRpcCall {
+            method,
+            size: Some(bytes.len()),
+        };
+        (Body::from(bytes), call)
+    };
+
+    parts.extensions.insert(call.clone());
+    Ok((Request::from_parts(parts, body), call))
+}
+
+/// Whether the request is a JSON-RPC call, which is only served over `POST`.
+///
+/// Other methods such as health checks and CORS preflights are forwarded untouched by the JSON-RPC
+/// metric layers. `POST` requests are recorded whatever their content type, so that calls from
+/// clients sending none and garbage traffic are counted, the latter as `invalid` or `unparseable`.
+pub(crate) fn is_json_rpc<B>(request: &Request<B>) -> bool {
+    request.method() == Method::POST || request.extensions().get::<RpcRequest>().is_some()
+}
+
+/// Reads propagated context such as `traceparent` or `baggage` from request headers.
+pub(crate) struct HeaderExtractor<'a>(pub(crate) &'a HeaderMap);
+
+impl Extractor for HeaderExtractor<'_> {
+    fn get(&self, key: &str) -> Option<&str> {
+        self.0.get(key)?.to_str().ok()
+    }
+
+    fn keys(&self) -> Vec<&str> {
+        self.0.keys().map(HeaderName::as_str).collect()
+    }
+}
+
+/// Only the error member is deserialized, the result is skipped without being allocated
+#[derive(Deserialize)]
+struct ResponseError {
+    error: Option<ErrorCode>,
+}
+
+#[derive(Deserialize)]
+struct ErrorCode {
+    code: i64,
+}
+
+/// Code of the JSON-RPC error carried by a response body, `None` for results and other bodies.
+pub(crate) fn error_code(body: &[u8]) -> Option<i64> {
+    serde_json::from_slice::<ResponseError>(body)
+        .ok()?
+        .error
+        .map(|error| error.code)
+}
+
+/// Largest response prefix kept to read its JSON-RPC error code. Error responses are small, so
+/// larger bodies are taken for results.
+const INSPECT_LIMIT: usize = 64 * 1024;
+
+/// Reads the JSON-RPC error code of the response body while it streams, without holding back any
+/// frame.
+///
+/// `on_end` is called once with whether the body was sent completely and the code, which is `None`
+/// for results and for bodies that fail or are dropped before their end.
+pub(crate) fn inspect_response(
+    response: Response,
+    on_end: impl FnOnce(bool, Option<i64>) + Send + 'static,
+) -> Response {
+    observe_response(
+        response,
+        ErrorCodeObserver {
+            prefix: Some(Vec::new()),
+            on_end: Box::new(on_end),
+        },
+    )
+}
+
+/// Keeps up to [`INSPECT_LIMIT`] bytes of the body to read its error code.
+struct ErrorCodeObserver {
+    /// `None` once the body exceeds the limit
+    prefix: Option<Vec<u8>>,
+    on_end: Box<dyn FnOnce(bool, Option<i64>) + Send>,
+}
+
+impl BodyObserver for ErrorCodeObserver {
+    fn data(&mut self, data: &Bytes) {
+        self.prefix = self
+            .prefix
+            .take()
+            .filter(|prefix| prefix.len() + data.len() <= INSPECT_LIMIT)
+            .map(|mut prefix| {
+                prefix.extend_from_slice(data);
+                prefix
+            });
+    }
+
+    fn end(self, complete: bool) {
+        let code = self
+            .prefix
+            .filter(|_| complete)
+            .as_deref()
+            .and_then(error_code);
+        (self.on_end)(complete, code);
+    }
+}
+
+/// Callbacks of an [`ObservedBody`].
+pub(crate) trait BodyObserver: Send + Unpin + 'static {
+    /// Called with every data frame as it is sent.
+    fn data(&mut self, _data: &Bytes) {}
+
+    /// Called once, `complete` is false if the body failed or was dropped before its end.
+    fn end(self, complete: bool);
+}
+
+/// Wraps the response body to notify `observer` of its frames and end.
+pub(crate) fn observe_response(response: Response, observer: impl BodyObserver) -> Response {
+    response.map(|body| Body::new(ObservedBody::new(body, observer)))
+}
+
+/// Body passing frames through to the client while notifying its [`BodyObserver`].
+pub(crate) struct ObservedBody<O: BodyObserver> {
+    inner: Body,
+    /// Taken once the body ends
+    observer: Option<O>,
+}
+
+impl<O: BodyObserver> ObservedBody<O> {
+    pub(crate) fn new(inner: Body, observer: O) -> Self {
+        let mut body = Self {
+            inner,
+            observer: Some(observer),
+        };
+        // Empty bodies may never be polled
+        if body.inner.is_end_stream() {
+            body.end(true);
+        }
+        body
+    }
+
+    fn end(&mut self, complete: bool) {
+        if let Some(observer) = self.observer.take() {
+            observer.end(complete);
+        }
+    }
+}
+
+impl<O: BodyObserver> HttpBody for ObservedBody<O> {
+    type Data = Bytes;
+    type Error = axum::Error;
+
+    fn poll_frame(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Frame<Self::Data>, Self::Error>>> {
+        let this = self.get_mut();
+        let poll = Pin::new(&mut this.inner).poll_frame(cx);
+
+        match &poll {
+            Poll::Ready(Some(Ok(frame))) => {
+                if let (Some(data), Some(observer)) = (frame.data_ref(), this.observer.as_mut()) {
+                    observer.data(data);
+                }
+                // The body isn't polled again once it reports its end
+                if this.inner.is_end_stream() {
+                    this.end(true);
+                }
+            }
+            Poll::Ready(Some(Err(_))) => this.end(false),
+            Poll::Ready(None) => this.end(true),
+            Poll::Pending => {}
+        }
+
+        poll
+    }
+
+    fn is_end_stream(&self) -> bool {
+        self.inner.is_end_stream()
+    }
+
+    fn size_hint(&self) -> SizeHint {
+        self.inner.size_hint()
+    }
+}
+
+impl<O: BodyObserver> Drop for ObservedBody<O> {
+    fn drop(&mut self) {
+        self.end(false);
+    }
+}
 
 pub fn create_response(message: &str) -> Response {
     let response =
@@ -39,6 +323,121 @@ mod tests {
     use super::*;
     use axum::body::to_bytes;
 
+    #[test]
+    fn test_method_label() {
+        assert_eq!(
+            method_label(br#"{"jsonrpc":"2.0","method":"eth_blockNumber","params":[],"id":1}"#),
+            "eth_blocknumber"
+        );
+        assert_eq!(method_label(br#"{"hello":"world"}"#), INVALID_METHOD);
+        assert_eq!(method_label(b"GET / HTTP/1.1"), UNPARSEABLE_METHOD);
+        assert_eq!(method_label(b""), UNPARSEABLE_METHOD);
+    }
+
+    #[test]
+    fn test_is_json_rpc() {
+        let request = |method: Method| Request::builder().method(method).body(()).unwrap();
+
+        assert!(is_json_rpc(&request(Method::POST)));
+        assert!(!is_json_rpc(&request(Method::GET)));
+        assert!(!is_json_rpc(&request(Method::OPTIONS)));
+
+        let mut parsed = request(Method::GET);
+        parsed.extensions_mut().insert(RpcRequest {
+            jsonrpc: "2.0".to_string(),
+            method: "eth_chainId".to_string(),
+            params: None,
+            id: Value::from(1),
+        });
+        assert!(is_json_rpc(&parsed));
+    }
+
+    #[tokio::test]
+    async fn test_read_call() {
+        let request = Request::post("/")
+            .body(Body::from(
+                r#"{"jsonrpc":"2.0","method":"eth_chainId","params":[],"id":1}"#,
+            ))
+            .unwrap();
+        let (request, call) = read_call(request, "test").await.unwrap();
+        assert_eq!(call.method, "eth_chainid");
+        assert_eq!(call.size, Some(59));
+        assert!(request.extensions().get::<RpcRequest>().is_some());
+
+        // Read from the extension by the layers further in
+        let (request, call) = read_call(request, "test").await.unwrap();
+        assert_eq!(call.method, "eth_chainid");
+        let body = to_bytes(request.into_body(), usize::MAX).await.unwrap();
+        assert_eq!(body.len(), 59);
+
+        let request = Request::post("/").body(Body::from("garbage")).unwrap();
+        let (request, call) = read_call(request, "test").await.unwrap();
+        assert_eq!(call.method, UNPARSEABLE_METHOD);
+        assert!(request.extensions().get::<RpcRequest>().is_none());
+    }
+
+    #[test]
+    fn test_error_code() {
+        assert_eq!(
+            error_code(br#"{"jsonrpc":"2.0","id":1,"error":{"code":-32601,"message":"x"}}"#),
+            Some(-32601)
+        );
+        assert_eq!(
+            error_code(br#"{"jsonrpc":"2.0","id":1,"result":{"error":{"code":1}}}"#),
+            None
+        );
+        assert_eq!(error_code(b"not json"), None);
+    }
+
+    #[tokio::test]
+    async fn test_inspect_response() {
+        use futures_util::stream;
+        use std::{
+            convert::Infallible,
+            sync::{Arc, Mutex},
+        };
+
+        let inspect = |chunks: Vec<String>| {
+            let ended = Arc::new(Mutex::new(None));
+            let body = Body::from_stream(stream::iter(chunks.into_iter().map(Ok::<_, Infallible>)));
+            let response = inspect_response(Response::new(body), {
+                let ended = ended.clone();
+                move |complete, code| *ended.lock().unwrap() = Some((complete, code))
+            });
+            (response, ended)
+        };
+
+        let (response, ended) = inspect(vec![
+            r#"{"jsonrpc":"2.0","id":1,"#.into(),
+            r#""error":{"code":-32000,"message":"x"}}"#.into(),
+        ]);
+        let mut body = response.into_body();
+        let frame = std::future::poll_fn(|cx| Pin::new(&mut body).poll_frame(cx))
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(frame.data_ref().unwrap(), r#"{"jsonrpc":"2.0","id":1,"#);
+        assert_eq!(*ended.lock().unwrap(), None);
+        while std::future::poll_fn(|cx| Pin::new(&mut body).poll_frame(cx))
+            .await
+            .is_some()
+        {}
+        assert_eq!(*ended.lock().unwrap(), Some((true, Some(-32000))));
+
+        // Past the limit the body is taken for a result
+        let padding = " ".repeat(INSPECT_LIMIT);
+        let (response, ended) = inspect(vec![
+            padding,
+            r#"{"jsonrpc":"2.0","id":1,"error":{"code":-32000,"message":"x"}}"#.into(),
+        ]);
+        to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        assert_eq!(*ended.lock().unwrap(), Some((true, None)));
+
+        let (response, ended) = inspect(vec![r#"{"jsonrpc":"2.0","#.into()]);
+        drop(response);
+        assert_eq!(*ended.lock().unwrap(), Some((false, None)));
+    }
+
     #[tokio::test]
     async fn test_create_response() {
         let response = create_response("Test error message");
@@ -0,0 +1,226 @@
+//! Middleware for generating and propagating request ids.
+//!
+//! Requests without the id header get a generated one. Incoming ids are kept if they are at most
+//! [`MAX_REQUEST_ID_LEN`] bytes of visible ASCII, and replaced by a generated one otherwise so that
+//! clients can't inject arbitrarily long values or control characters into every log. The id is
+//! inserted as a [`RequestId`] extension, recorded as the `trace_id` field of the
+//! [`trace_layer`](super::trace_layer) span and therefore on every log within it, and echoed in
+//! the response. The layer must be added outside the trace layer for the span to see the id.
+
+use crate::middleware::HeaderExtractor;
+use axum::http::{HeaderName, HeaderValue, Request};
+use opentelemetry::{
+    propagation::TextMapPropagator,
+    trace::{TraceContextExt, TraceId},
+};
+use opentelemetry_sdk::propagation::TraceContextPropagator;
+use std::task::{Context, Poll};
+use tower::{Layer, Service};
+use tower_http::request_id::{MakeRequestId, PropagateRequestId, RequestId, SetRequestId};
+use uuid::Uuid;
+
+const X_REQUEST_ID: HeaderName = HeaderName::from_static("x-request-id");
+
+/// Longest incoming request id that is kept.
+pub const MAX_REQUEST_ID_LEN: usize = 128;
+
+/// Whether an incoming id is short enough and only contains visible ASCII characters.
+fn is_valid_request_id(value: &HeaderValue) -> bool {
+    value.len() <= MAX_REQUEST_ID_LEN && value.as_bytes().iter().all(u8::is_ascii_graphic)
+}
+
+/// How missing request ids are generated.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum RequestIdFormat {
+    /// Time ordered UUID, sortable by arrival.
+    #[default]
+    UuidV7,
+    /// Trace id of the incoming `traceparent` header, a UUIDv7 for requests without one.
+    TraceId,
+}
+
+#[derive(Clone, Copy, Debug, Default)]
+pub struct MakeTelemetryRequestId {
+    format: RequestIdFormat,
+}
+
+impl MakeRequestId for MakeTelemetryRequestId {
+    fn make_request_id<B>(&mut self, request: &Request<B>) -> Option<RequestId> {
+        let id = match self.format {
+            RequestIdFormat::TraceId => trace_id(request)
+                .map(|trace_id| trace_id.to_string())
+                .unwrap_or_else(|| Uuid::now_v7().to_string()),
+            RequestIdFormat::UuidV7 => Uuid::now_v7().to_string(),
+        };
+
+        HeaderValue::from_str(&id).ok().map(RequestId::new)
+    }
+}
+
+fn trace_id<B>(request: &Request<B>) -> Option<TraceId> {
+    let context = TraceContextPropagator::new().extract(&HeaderExtractor(request.headers()));
+    let span = context.span();
+    let span_context = span.span_context();
+    span_context.is_valid().then(|| span_context.trace_id())
+}
+
+#[derive(Clone, Debug)]
+pub struct RequestIdLayer {
+    header: HeaderName,
+    make_request_id: MakeTelemetryRequestId,
+}
+
+impl RequestIdLayer {
+    pub fn builder() -> RequestIdLayerBuilder {
+        RequestIdLayerBuilder {
+            header: X_REQUEST_ID,
+            format: RequestIdFormat::default(),
+        }
+    }
+}
+
+/// UUIDv7 ids in the `x-request-id` header.
+impl Default for RequestIdLayer {
+    fn default() -> Self {
+        Self::builder().build()
+    }
+}
+
+pub struct RequestIdLayerBuilder {
+    header: HeaderName,
+    format: RequestIdFormat,
+}
+
+impl RequestIdLayerBuilder {
+    /// Header the id is read from and echoed in, defaults to `x-request-id`.
+    pub fn header(mut self, header: HeaderName) -> Self {
+        self.header = header;
+        self
+    }
+
+    pub fn format(mut self, format: RequestIdFormat) -> Self {
+        self.format = format;
+        self
+    }
+
+    pub fn build(self) -> RequestIdLayer {
+        RequestIdLayer {
+            header: self.header,
+            make_request_id: MakeTelemetryRequestId {
+                format: self.format,
+            },
+        }
+    }
+}
+
+impl<S> Layer<S> for RequestIdLayer {
+    type Service = ValidateRequestId<SetRequestId<PropagateRequestId<S>, MakeTelemetryRequestId>>;
+    fn layer(&self, inner: S) -> Self::Service {
+        ValidateRequestId {
+            inner: SetRequestId::new(
+                PropagateRequestId::new(inner, self.header.clone()),
+                self.header.clone(),
+                self.make_request_id,
+            ),
+            header: self.header.clone(),
+        }
+    }
+}
+
+/// Removes invalid incoming ids so that [`SetRequestId`] generates a new one.
+#[derive(Clone, Debug)]
+pub struct ValidateRequestId<S> {
+    inner: S,
+    header: HeaderName,
+}
+
+impl<S, B> Service<Request<B>> for ValidateRequestId<S>
+where
+    S: Service<Request<B>>,
+{
+    type Response = S::Response;
+    type Error = S::Error;
+    type Future = S::Future;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, mut request: Request<B>) -> Self::Future {
+        if request
+            .headers()
+            .get(&self.header)
+            .is_some_and(|value| !is_valid_request_id(value))
+        {
+            request.headers_mut().remove(&self.header);
+        }
+        self.inner.call(request)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::{body::Body, response::Response};
+    use std::convert::Infallible;
+    use tower::{ServiceExt, service_fn};
+
+    async fn request_id(layer: RequestIdLayer, request: Request<Body>) -> (String, String) {
+        let service = layer.layer(service_fn(|request: Request<Body>| async move {
+            let id = request.extensions().get::<RequestId>().cloned().unwrap();
+            let mut response = Response::new(Body::empty());
+            response.extensions_mut().insert(id);
+            Ok::<_, Infallible>(response)
+        }));
+
+        let response = service.oneshot(request).await.unwrap();
+        let extension = response.extensions().get::<RequestId>().unwrap();
+        let extension = extension.header_value().to_str().unwrap().to_string();
+        let header = response.headers()[X_REQUEST_ID]
+            .to_str()
+            .unwrap()
+            .to_string();
+        (extension, header)
+    }
+
+    #[tokio::test]
+    async fn test_request_id() {
+        let (extension, header) = request_id(RequestIdLayer::default(), Request::default()).await;
+        assert_eq!(extension, header);
+        assert_eq!(Uuid::parse_str(&header).unwrap().get_version_num(), 7);
+
+        let request = Request::builder()
+            .header(X_REQUEST_ID, "incoming")
+            .body(Body::empty())
+            .unwrap();
+        let (_, header) = request_id(RequestIdLayer::default(), request).await;
+        assert_eq!(header, "incoming");
+
+        let too_long = "a".repeat(MAX_REQUEST_ID_LEN + 1);
+        for invalid in [too_long.as_str(), "with space", "tab\tid", "caf\u{e9}"] {
+            let request = Request::builder()
+                .header(
+                    X_REQUEST_ID,
+                    HeaderValue::from_bytes(invalid.as_bytes()).unwrap(),
+                )
+                .body(Body::empty())
+                .unwrap();
+            let (extension, header) = request_id(RequestIdLayer::default(), request).await;
+            assert_eq!(extension, header);
+            assert_eq!(Uuid::parse_str(&header).unwrap().get_version_num(), 7);
+        }
+
+        let layer = RequestIdLayer::builder()
+            .format(RequestIdFormat::TraceId)
+            .build();
+        let request = Request::builder()
+            .header(
+                "traceparent",
+                "00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01",
+            )
+            .body(Body::empty())
+            .unwrap();
+        let (_, header) = request_id(layer, request).await;
+        assert_eq!(header, "4bf92f3577b34da6a3ce929d0e0e4736");
+    }
+}
@@ -0,0 +1,493 @@
+//! Middleware for tracking per-method service level objectives.
+//!
+//! Every call to a method with objectives is classified as good or bad for each of them and
+//! counted in `slo_good_total` and `slo_total` with the `method` and `slo` attributes. Methods
+//! without their own objectives fall back to the default objectives under `method="other"`, which
+//! keeps the cardinality bounded by the configuration.
+//!
+//! The layer also keeps a minute resolution history of the last six hours to expose the
+//! `slo_burn_rate` gauge for the 5m, 30m, 1h and 6h windows used by multi-window burn-rate alerts.
+//! A burn rate of 1 consumes the error budget exactly over the SLO period.
+
+use crate::middleware::{JsonRpcCall, inspect_response, is_json_rpc, read_call};
+use axum::{body::Body, http::Request, response::Response};
+use eyre::{Result, eyre};
+use futures_util::future::BoxFuture;
+use opentelemetry::{KeyValue, global, metrics::Counter};
+use std::{
+    collections::{HashMap, VecDeque},
+    convert::Infallible,
+    sync::{Arc, Mutex},
+    task::{Context, Poll},
+    time::{Duration, Instant},
+};
+use tower::{Layer, Service};
+
+/// Method label of calls to methods without their own objectives
+const OTHER_METHOD: &str = "other";
+
+const BURN_RATE_WINDOWS: &[(&str, u64)] = &[("5m", 5), ("30m", 30), ("1h", 60), ("6h", 360)];
+
+/// Minutes of history kept per objective, the longest burn-rate window
+const HISTORY_MINUTES: u64 = 360;
+
+/// JSON-RPC errors caused by the request itself, they don't consume the error budget
+const CLIENT_ERROR_CODES: &[i64] = &[-32700, -32600, -32601, -32602];
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum SloKind {
+    /// Successful HTTP status without a JSON-RPC server error
+    Availability,
+    /// Handled within the threshold
+    Latency(Duration),
+}
+
+/// Objective of a JSON-RPC method, reported under the `slo` attribute.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct SloObjective {
+    kind: SloKind,
+    target: f64,
+}
+
+impl SloObjective {
+    /// Fraction of calls that must succeed, such as `0.999`.
+    ///
+    /// Calls fail on a non-2xx status or a JSON-RPC error other than parse error, invalid request,
+    /// method not found and invalid params, or when the response isn't sent completely.
+    ///
+    /// Fails if `target` is not within `(0, 1)`.
+    pub fn availability(target: f64) -> Result<Self> {
+        Self::new(SloKind::Availability, target)
+    }
+
+    /// Fraction of calls that must complete within `threshold`, `latency(200ms, 0.99)` is a p99
+    /// objective of 200ms. Calls complete once the last byte of their response is sent.
+    ///
+    /// Fails if `target` is not within `(0, 1)`.
+    pub fn latency(threshold: Duration, target: f64) -> Result<Self> {
+        Self::new(SloKind::Latency(threshold), target)
+    }
+
+    fn new(kind: SloKind, target: f64) -> Result<Self> {
+        if !(target > 0.0 && target < 1.0) {
+            return Err(eyre!("SLO target must be within (0, 1), got {target}"));
+        }
+        Ok(Self { kind, target })
+    }
+
+    fn name(&self) -> &'static str {
+        match self.kind {
+            SloKind::Availability => "availability",
+            SloKind::Latency(_) => "latency",
+        }
+    }
+
+    /// `elapsed` is `None` for calls cancelled before their response is sent, which are bad.
+    fn is_good(&self, success: bool, elapsed: Option<Duration>) -> bool {
+        match self.kind {
+            SloKind::Availability => success,
+            SloKind::Latency(threshold) => elapsed.is_some_and(|elapsed| elapsed <= threshold),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default)]
+struct Bucket {
+    minute: u64,
+    good: u64,
+    total: u64,
+}
+
+/// Good and total calls per minute of one method and objective.
+#[derive(Debug)]
+struct History {
+    target: f64,
+    buckets: VecDeque<Bucket>,
+}
+
+impl History {
+    fn record(&mut self, minute: u64, good: bool) {
+        match self.buckets.back_mut() {
+            Some(bucket) if bucket.minute == minute => {
+                bucket.good += u64::from(good);
+                bucket.total += 1;
+            }
+            _ => self.buckets.push_back(Bucket {
+                minute,
+                good: u64::from(good),
+                total: 1,
+            }),
+        }
+
+        while self
+            .buckets
+            .front()
+            .is_some_and(|bucket| bucket.minute + HISTORY_MINUTES <= minute)
+        {
+            self.buckets.pop_front();
+        }
+    }
+
+    /// Ratio of the error rate over the last `minutes` to the error budget, `None` without calls.
+    fn burn_rate(&self, minute: u64, minutes: u64) -> Option<f64> {
+        let (good, total) = self
+            .buckets
+            .iter()
+            .filter(|bucket| bucket.minute + minutes > minute)
+            .fold((0, 0), |(good, total), bucket| {
+                (good + bucket.good, total + bucket.total)
+            });
+
+        if total == 0 {
+            return None;
+        }
+
+        let error_rate = (total - good) as f64 / total as f64;
+        Some(error_rate / (1.0 - self.target))
+    }
+}
+
+struct Tracker {
+    started: Instant,
+    histories: Mutex<HashMap<(String, &'static str), History>>,
+}
+
+impl Tracker {
+    fn minute(&self, now: Instant) -> u64 {
+        now.duration_since(self.started).as_secs() / 60
+    }
+
+    fn record(&self, method: &str, objective: &SloObjective, good: bool, now: Instant) {
+        let minute = self.minute(now);
+        let Ok(mut histories) = self.histories.lock() else {
+            return;
+        };
+        histories
+            .entry((method.to_string(), objective.name()))
+            .or_insert_with(|| History {
+                target: objective.target,
+                buckets: VecDeque::new(),
+            })
+            .record(minute, good);
+    }
+}
+
+#[derive(Clone)]
+pub struct SloLayer {
+    objectives: Arc<HashMap<String, Vec<SloObjective>>>,
+    default_objectives: Arc<[SloObjective]>,
+    good: Counter<u64>,
+    total: Counter<u64>,
+    tracker: Arc<Tracker>,
+}
+
+impl SloLayer {
+    pub fn builder() -> SloLayerBuilder {
+        SloLayerBuilder {
+            objectives: HashMap::new(),
+            default_objectives: Vec::new(),
+        }
+    }
+
+    /// Objectives of a method and the label they are reported under.
+    fn objectives<'a>(&'a self, method: &'a str) -> (&'a str, &'a [SloObjective]) {
+        match self.objectives.get(method) {
+            Some(objectives) => (method, objectives),
+            None => (OTHER_METHOD, &self.default_objectives),
+        }
+    }
+
+    fn record(&self, method: &str, success: bool, elapsed: Option<Duration>) {
+        let (label, objectives) = self.objectives(method);
+        let now = Instant::now();
+
+        for objective in objectives {
+            let good = objective.is_good(success, elapsed);
+            let attributes = [
+                KeyValue::new("method", label.to_string()),
+                KeyValue::new("slo", objective.name()),
+            ];
+
+            self.total.add(1, &attributes);
+            if good {
+                self.good.add(1, &attributes);
+            }
+            self.tracker.record(label, objective, good, now);
+        }
+    }
+}
+
+/// Configures the objectives, at most one of each kind per method.
+pub struct SloLayerBuilder {
+    objectives: HashMap<String, Vec<SloObjective>>,
+    default_objectives: Vec<SloObjective>,
+}
+
+impl SloLayerBuilder {
+    /// Adds an objective for `method`, matched case-insensitively.
+    pub fn objective(mut self, method: &str, objective: SloObjective) -> Self {
+        let objectives = self.objectives.entry(method.to_lowercase()).or_default();
+        add_objective(objectives, objective);
+        self
+    }
+
+    /// Adds an objective for all methods without their own objectives.
+    pub fn default_objective(mut self, objective: SloObjective) -> Self {
+        add_objective(&mut self.default_objectives, objective);
+        self
+    }
+
+    pub fn build(self) -> SloLayer {
+        let meter = global::meter("jsonrpc");
+        let good = meter.u64_counter("slo_good_total").build();
+        let total = meter.u64_counter("slo_total").build();
+
+        let tracker = Arc::new(Tracker {
+            started: Instant::now(),
+            histories: Mutex::default(),
+        });
+
+        let gauge_tracker = tracker.clone();
+        meter
+            .f64_observable_gauge("slo_burn_rate")
+            .with_callback(move |observer| {
+                let minute = gauge_tracker.minute(Instant::now());
+                let Ok(histories) = gauge_tracker.histories.lock() else {
+                    return;
+                };
+                for ((method, slo), history) in histories.iter() {
+                    for (window, minutes) in BURN_RATE_WINDOWS {
+                        if let Some(burn_rate) = history.burn_rate(minute, *minutes) {
+                            observer.observe(
+                                burn_rate,
+                                &[
+                                    KeyValue::new("method", method.clone()),
+                                    KeyValue::new("slo", *slo),
+                                    KeyValue::new("window", *window),
+                                ],
+                            );
+                        }
+                    }
+                }
+            })
+            .build();
+
+        SloLayer {
+            objectives: Arc::new(self.objectives),
+            default_objectives: self.default_objectives.into(),
+            good,
+            total,
+            tracker,
+        }
+    }
+}
+
+/// Replaces an objective of the same kind
+fn add_objective(objectives: &mut Vec<SloObjective>, objective: SloObjective) {
+    objectives.retain(|existing| existing.name() != objective.name());
+    objectives.push(objective);
+}
+
+impl<S> Layer<S> for SloLayer {
+    type Service = Slo<S>;
+    fn layer(&self, inner: S) -> Self::Service {
+        Slo {
+            inner,
+            layer: self.clone(),
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct Slo<S> {
+    inner: S,
+    layer: SloLayer,
+}
+
+impl<S> Service<Request<Body>> for Slo<S>
+where
+    S: Service<Request<Body>, Response = Response, Error = Infallible> + Clone + Send + 'static,
+    S::Future: Send + 'static,
+{
+    type Response = S::Response;
+    type Error = Infallible;
+    type Future = BoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, request: Request<Body>) -> Self::Future {
+        let mut inner = self.inner.clone();
+        if !is_json_rpc(&request) {
+            return Box::pin(async move { inner.call(request).await });
+        }
+        let layer = self.layer.clone();
+
+        Box::pin(async move {
+            let start = Instant::now();
+            let (request, JsonRpcCall { method, .. }) = match read_call(request, "Slo").await {
+                Ok(read) => read,
+                Err(response) => return Ok(response),
+            };
+
+            let (_, objectives) = layer.objectives(&method);
+            if objectives.is_empty() {
+                return inner.call(request).await;
+            }
+
+            let mut guard = CancelGuard(Some((layer, method)));
+            let response = inner.call(request).await?;
+            let Some((layer, method)) = guard.0.take() else {
+                unreachable!("armed until the response is returned")
+            };
+
+            // Recorded once the body ends, bodies dropped before are cancelled
+            let status_success = response.status().is_success();
+            let response = inspect_response(response, move |complete, code| {
+                if !complete {
+                    layer.record(&method, false, None);
+                    return;
+                }
+                let success =
+                    status_success && code.is_none_or(|code| CLIENT_ERROR_CODES.contains(&code));
+                layer.record(&method, success, Some(start.elapsed()));
+            });
+
+            Ok(response)
+        })
+    }
+}
+
+/// Records the call as bad for every objective when the future is dropped before the response.
+struct CancelGuard(Option<(SloLayer, String)>);
+
+impl Drop for CancelGuard {
+    fn drop(&mut self) {
+        if let Some((layer, method)) = &self.0 {
+            layer.record(method, false, None);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::body::to_bytes;
+    use tower::{ServiceExt, service_fn};
+
+    #[test]
+    fn test_burn_rate_windows() {
+        let mut history = History {
+            target: 0.99,
+            buckets: VecDeque::new(),
+        };
+
+        // 10% errors an hour ago, none in the last five minutes
+        for _ in 0..90 {
+            history.record(0, true);
+        }
+        for _ in 0..10 {
+            history.record(0, false);
+        }
+        for _ in 0..100 {
+            history.record(59, true);
+        }
+
+        assert_eq!(history.burn_rate(59, 5), Some(0.0));
+        let burn_rate = history.burn_rate(59, 60).unwrap();
+        assert!((burn_rate - 5.0).abs() < 1e-9);
+        assert_eq!(history.burn_rate(500, 5), None);
+
+        history.record(420, true);
+        assert_eq!(history.buckets.len(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_classifies_jsonrpc_errors() {
+        let layer = SloLayer::builder()
+            .objective("eth_call", SloObjective::availability(0.999).unwrap())
+            .objective(
+                "eth_call",
+                SloObjective::latency(Duration::from_secs(1), 0.99).unwrap(),
+            )
+            .build();
+
+        let service = layer
+            .clone()
+            .layer(service_fn(|request: Request<Body>| async move {
+                let body = to_bytes(request.into_body(), usize::MAX).await.unwrap();
+                let body = if body.windows(4).any(|window| window == b"fail") {
+                    r#"{"jsonrpc":"2.0","id":1,"error":{"code":-32603,"message":"internal"}}"#
+                } else {
+                    r#"{"jsonrpc":"2.0","id":1,"error":{"code":-32602,"message":"params"}}"#
+                };
+                Ok::<_, Infallible>(Response::new(Body::from(body)))
+            }));
+
+        for params in ["fail", "ok"] {
+            let body =
+                format!(r#"{{"jsonrpc":"2.0","method":"eth_call","params":["{params}"],"id":1}}"#);
+            let request = Request::post("/")
+                .header("content-type", "application/json")
+                .body(Body::from(body))
+                .unwrap();
+            let response = service.clone().oneshot(request).await.unwrap();
+            let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+            assert!(body.starts_with(br#"{"jsonrpc""#));
+        }
+
+        assert!(SloObjective::availability(1.0).is_err());
+        assert!(SloObjective::latency(Duration::from_secs(1), f64::NAN).is_err());
+
+        let histories = layer.tracker.histories.lock().unwrap();
+        let availability = &histories[&("eth_call".to_string(), "availability")];
+        assert_eq!(
+            (availability.buckets[0].good, availability.buckets[0].total),
+            (1, 2)
+        );
+        let latency = &histories[&("eth_call".to_string(), "latency")];
+        assert_eq!((latency.buckets[0].good, latency.buckets[0].total), (2, 2));
+    }
+
+    #[tokio::test]
+    async fn test_cancelled_calls_are_bad() {
+        let layer = SloLayer::builder()
+            .objective("eth_call", SloObjective::availability(0.999).unwrap())
+            .objective(
+                "eth_call",
+                SloObjective::latency(Duration::from_secs(1), 0.99).unwrap(),
+            )
+            .build();
+        let request = || {
+            Request::post("/")
+                .body(Body::from(
+                    r#"{"jsonrpc":"2.0","method":"eth_call","params":[],"id":1}"#,
+                ))
+                .unwrap()
+        };
+
+        let mut service = layer.clone().layer(service_fn(|_request: Request<Body>| {
+            std::future::pending::<Result<Response, Infallible>>()
+        }));
+        let mut future = service.call(request());
+        assert!(futures_util::poll!(&mut future).is_pending());
+        drop(future);
+
+        // Dropped while the response streams
+        let service = layer
+            .clone()
+            .layer(service_fn(|_request: Request<Body>| async {
+                let chunks = futures_util::stream::pending::<Result<String, Infallible>>();
+                Ok::<_, Infallible>(Response::new(Body::from_stream(chunks)))
+            }));
+        let response = service.oneshot(request()).await.unwrap();
+        drop(response);
+
+        let histories = layer.tracker.histories.lock().unwrap();
+        for slo in ["availability", "latency"] {
+            let history = &histories[&("eth_call".to_string(), slo)];
+            assert_eq!((history.buckets[0].good, history.buckets[0].total), (0, 2));
+        }
+    }
+}
@@ -0,0 +1,336 @@
+//! Middleware for logging JSON-RPC calls slower than a per-method threshold.
+//!
+//! Slow calls are logged at warn level with the method, id, truncated params, client identity and
+//! request/response sizes, including calls cancelled after exceeding their threshold.
+//!
+//! Slow calls don't force their trace to be sampled: the SDK sampler decides when the span starts,
+//! so the trace of a slow call is only exported if it was sampled anyway. The log is emitted within
+//! the request span and carries its trace id either way.
+
+use crate::middleware::{
+    ClientIdentity, JsonRpcCall, http_metrics::body_size, is_json_rpc, read_call,
+};
+use axum::{body::Body, http::Request, response::Response};
+use futures_util::future::BoxFuture;
+use rpc::Request as RpcRequest;
+use serde::Serialize;
+use std::{
+    collections::HashMap,
+    convert::Infallible,
+    io,
+    sync::Arc,
+    task::{Context, Poll},
+    time::{Duration, Instant},
+};
+use tower::{Layer, Service};
+use tracing::{Span, warn};
+
+const DEFAULT_THRESHOLD: Duration = Duration::from_secs(1);
+const DEFAULT_MAX_PARAMS_LEN: usize = 256;
+
+#[derive(Clone)]
+pub struct SlowRequestLayer {
+    thresholds: Arc<HashMap<String, Duration>>,
+    default_threshold: Duration,
+    max_params_len: usize,
+}
+
+impl SlowRequestLayer {
+    pub fn builder() -> SlowRequestLayerBuilder {
+        SlowRequestLayerBuilder {
+            thresholds: HashMap::new(),
+            default_threshold: DEFAULT_THRESHOLD,
+            max_params_len: DEFAULT_MAX_PARAMS_LEN,
+        }
+    }
+
+    fn threshold(&self, method: &str) -> Duration {
+        self.thresholds
+            .get(method)
+            .copied()
+            .unwrap_or(self.default_threshold)
+    }
+}
+
+impl Default for SlowRequestLayer {
+    fn default() -> Self {
+        Self::builder().build()
+    }
+}
+
+pub struct SlowRequestLayerBuilder {
+    thresholds: HashMap<String, Duration>,
+    default_threshold: Duration,
+    max_params_len: usize,
+}
+
+impl SlowRequestLayerBuilder {
+    /// Threshold of `method`, matched case-insensitively.
+    pub fn threshold(mut self, method: &str, threshold: Duration) -> Self {
+        self.thresholds.insert(method.to_lowercase(), threshold);
+        self
+    }
+
+    /// Threshold of methods without their own, defaults to a second.
+    pub fn default_threshold(mut self, threshold: Duration) -> Self {
+        self.default_threshold = threshold;
+        self
+    }
+
+    /// Maximum number of characters of the serialized params that are logged, defaults to 256.
+    pub fn max_params_len(mut self, max_params_len: usize) -> Self {
+        self.max_params_len = max_params_len;
+        self
+    }
+
+    pub fn build(self) -> SlowRequestLayer {
+        SlowRequestLayer {
+            thresholds: Arc::new(self.thresholds),
+            default_threshold: self.default_threshold,
+            max_params_len: self.max_params_len,
+        }
+    }
+}
+
+impl<S> Layer<S> for SlowRequestLayer {
+    type Service = SlowRequest<S>;
+    fn layer(&self, inner: S) -> Self::Service {
+        SlowRequest {
+            inner,
+            layer: self.clone(),
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct SlowRequest<S> {
+    inner: S,
+    layer: SlowRequestLayer,
+}
+
+impl<S> Service<Request<Body>> for SlowRequest<S>
+where
+    S: Service<Request<Body>, Response = Response, Error = Infallible> + Clone + Send + 'static,
+    S::Future: Send + 'static,
+{
+    type Response = S::Response;
+    type Error = Infallible;
+    type Future = BoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, request: Request<Body>) -> Self::Future {
+        let mut inner = self.inner.clone();
+        if !is_json_rpc(&request) {
+            return Box::pin(async move { inner.call(request).await });
+        }
+        let layer = self.layer.clone();
+
+        Box::pin(async move {
+            let start = Instant::now();
+            let (
+                request,
+                JsonRpcCall {
+                    method,
+                    size: request_size,
+                },
+            ) = match read_call(request, "SlowRequest").await {
+                Ok(read) => read,
+                Err(response) => return Ok(response),
+            };
+
+            let client = request
+                .extensions()
+                .get::<ClientIdentity>()
+                .map(|identity| identity.id().to_string());
+
+            // Bounded by `max_params_len`, so cheap enough to do upfront while the request is at hand
+            let (id, params) = request
+                .extensions()
+                .get::<RpcRequest>()
+                .map(|json_rpc| {
+                    let id = serialize_truncated(&json_rpc.id, layer.max_params_len);
+                    let params = serialize_truncated(&json_rpc.params, layer.max_params_len);
+                    (id, params)
+                })
+                .unzip();
+            let mut guard = CancelGuard(Some(Call {
+                threshold: layer.threshold(&method),
+                method,
+                id,
+                params,
+                client,
+                request_size,
+                start,
+                span: Span::current(),
+            }));
+
+            let response = inner.call(request).await?;
+            let Some(call) = guard.0.take() else {
+                unreachable!("armed until the response is returned")
+            };
+            call.log(body_size(response.headers(), response.body()), false);
+
+            Ok(response)
+        })
+    }
+}
+
+/// Call logged once it exceeds its threshold.
+struct Call {
+    method: String,
+    id: Option<String>,
+    params: Option<String>,
+    client: Option<String>,
+    request_size: Option<usize>,
+    threshold: Duration,
+    start: Instant,
+    /// Captured while polled, the future isn't dropped within the request span
+    span: Span,
+}
+
+impl Call {
+    fn log(&self, response_size: Option<u64>, cancelled: bool) {
+        let latency = self.start.elapsed();
+        if latency <= self.threshold {
+            return;
+        }
+
+        warn!(
+            parent: &self.span,
+            method = self.method,
+            id = self.id,
+            params = self.params,
+            client = self.client,
+            request_size = self.request_size,
+            response_size,
+            latency_ms = latency.as_millis(),
+            threshold_ms = self.threshold.as_millis(),
+            cancelled,
+            "Slow request"
+        );
+    }
+}
+
+/// Logs calls cancelled past their threshold, whose future is dropped before the response.
+struct CancelGuard(Option<Call>);
+
+impl Drop for CancelGuard {
+    fn drop(&mut self) {
+        if let Some(call) = &self.0 {
+            call.log(None, true);
+        }
+    }
+}
+
+/// Serializes `value` as JSON truncated to `max_len` characters, without serializing the rest.
+fn serialize_truncated(value: &impl Serialize, max_len: usize) -> String {
+    // A character is at most 4 bytes, one more makes truncated output exceed `max_len`
+    let mut writer = BoundedWriter {
+        buf: Vec::new(),
+        limit: max_len.saturating_add(1).saturating_mul(4),
+    };
+    // Fails once the limit is reached, the prefix written until then is kept
+    let _ = serde_json::to_writer(&mut writer, value);
+    truncate(String::from_utf8_lossy(&writer.buf).into_owned(), max_len)
+}
+
+/// Keeps the first `limit` bytes written and fails afterwards to stop the serialization early.
+struct BoundedWriter {
+    buf: Vec<u8>,
+    limit: usize,
+}
+
+impl io::Write for BoundedWriter {
+    fn write(&mut self, data: &[u8]) -> io::Result<usize> {
+        let remaining = self.limit - self.buf.len();
+        if remaining == 0 {
+            return Err(io::ErrorKind::WriteZero.into());
+        }
+        let len = data.len().min(remaining);
+        self.buf.extend_from_slice(&data[..len]);
+        Ok(len)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Truncates to `max_len` characters, marking truncated strings with an ellipsis.
+fn truncate(mut value: String, max_len: usize) -> String {
+    if let Some((index, _)) = value.char_indices().nth(max_len) {
+        value.truncate(index);
+        value.push('…');
+    }
+    value
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{fmt, sync::Mutex};
+    use tower::service_fn;
+    use tracing::{
+        Event, Subscriber,
+        field::{Field, Visit},
+    };
+    use tracing_subscriber::{layer::SubscriberExt, registry};
+
+    /// Collects the `cancelled` field of slow request logs.
+    #[derive(Clone, Default)]
+    struct SlowLogs(Arc<Mutex<Vec<bool>>>);
+
+    impl Visit for SlowLogs {
+        fn record_bool(&mut self, field: &Field, value: bool) {
+            if field.name() == "cancelled" {
+                self.0.lock().unwrap().push(value);
+            }
+        }
+
+        fn record_debug(&mut self, _field: &Field, _value: &dyn fmt::Debug) {}
+    }
+
+    impl<S: Subscriber> tracing_subscriber::Layer<S> for SlowLogs {
+        fn on_event(&self, event: &Event<'_>, _ctx: tracing_subscriber::layer::Context<'_, S>) {
+            event.record(&mut self.clone());
+        }
+    }
+
+    #[test]
+    fn test_truncate() {
+        assert_eq!(truncate("[\"0x1\"]".to_string(), 16), "[\"0x1\"]");
+        assert_eq!(truncate("[\"0x1234\"]".to_string(), 4), "[\"0x…");
+        assert_eq!(truncate("ééé".to_string(), 2), "éé…");
+
+        let params = serde_json::json!(["0x1", "é".repeat(1000)]);
+        assert_eq!(serialize_truncated(&params, 9), "[\"0x1\",\"é…");
+        assert_eq!(serialize_truncated(&params[0], 8), "\"0x1\"");
+    }
+
+    #[tokio::test]
+    async fn test_logs_cancelled_slow_requests() {
+        let logs = SlowLogs::default();
+        let _guard = tracing::subscriber::set_default(registry().with(logs.clone()));
+
+        let layer = SlowRequestLayer::builder()
+            .default_threshold(Duration::ZERO)
+            .build();
+        let mut service = layer.layer(service_fn(|_request: Request<Body>| {
+            std::future::pending::<Result<Response, Infallible>>()
+        }));
+
+        let request = Request::post("/")
+            .body(Body::from(
+                r#"{"jsonrpc":"2.0","method":"eth_getLogs","params":[],"id":1}"#,
+            ))
+            .unwrap();
+        let mut future = service.call(request);
+        assert!(futures_util::poll!(&mut future).is_pending());
+        drop(future);
+
+        assert_eq!(*logs.0.lock().unwrap(), [true]);
+    }
+}
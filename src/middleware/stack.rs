@@ -0,0 +1,367 @@
+//! Middleware stack composing the telemetry layers in the order they depend on each other.
+//!
+//! From the outermost to the innermost layer:
+//!
+//! 1. [`RequestIdLayer`] sets the id before the span is created so that it is recorded on it.
+//! 2. [`trace_layer`](super::trace_layer) creates the request span, every layer below runs within
+//!    it and their logs are parented to it. Its innermost
+//!    [`JsonRpcErrorLayer`](super::JsonRpcErrorLayer) reports JSON-RPC error responses as failures
+//!    of the span once their body ends, without holding back any frame of it.
+//! 3. [`ClientIdentityLayer`] records the client on the span and inserts the extension read by the
+//!    metric layers.
+//! 4. [`HttpMetricsLayer`] records every request, including the ones rejected below.
+//! 5. [`RequestValidationLayer`] rejects invalid requests and inserts the parsed request so that
+//!    the layers below don't buffer and parse the body again. Rejected requests therefore only
+//!    show up in the HTTP metrics and traces.
+//! 6. [`SlowRequestLayer`], [`SloLayer`], [`HeavyHittersLayer`], [`JsonRpcMethodInFlightLayer`],
+//!    [`JsonRpcMethodCounterLayer`] and [`JsonRpcMethodHistogramLayer`].
+//!
+//! No layer buffers the response, it streams through them so that the time to first and last
+//! byte recorded by the innermost [`JsonRpcMethodHistogramLayer`] is what the client sees.
+
+use crate::middleware::{
+    ClientIdentityLayer, HeavyHittersLayer, HttpMetricsLayer, JsonRpcMethodCounterLayer,
+    JsonRpcMethodHistogramLayer, JsonRpcMethodInFlightLayer, RequestIdLayer,
+    RequestValidationLayer, SloLayer, SlowRequestLayer, TraceLayerBuilder,
+};
+use axum::{body::Body, http::Request, response::Response};
+use std::convert::Infallible;
+use tower::{Layer, Service, ServiceExt, util::BoxCloneSyncService};
+
+/// Service produced by [`JsonRpcTelemetryLayer`].
+pub type JsonRpcTelemetryService = BoxCloneSyncService<Request<Body>, Response, Infallible>;
+
+#[derive(Clone)]
+pub struct JsonRpcTelemetryLayer {
+    request_id: Option<RequestIdLayer>,
+    trace: Option<TraceLayerBuilder>,
+    client_identity: Option<ClientIdentityLayer>,
+    http_metrics: Option<HttpMetricsLayer>,
+    error_classification: bool,
+    validation: bool,
+    slow_requests: Option<SlowRequestLayer>,
+    slo: Option<SloLayer>,
+    heavy_hitters: Option<HeavyHittersLayer>,
+    in_flight: Option<JsonRpcMethodInFlightLayer>,
+    method_counter: Option<JsonRpcMethodCounterLayer>,
+    method_histogram: Option<JsonRpcMethodHistogramLayer>,
+}
+
+impl JsonRpcTelemetryLayer {
+    /// Request ids, tracing with JSON-RPC error classification, validation and the method counter
+    /// and histogram are enabled by default, the other layers once configured.
+    pub fn builder() -> JsonRpcTelemetryLayerBuilder {
+        JsonRpcTelemetryLayerBuilder {
+            layer: JsonRpcTelemetryLayer {
+                request_id: Some(RequestIdLayer::default()),
+                trace: Some(TraceLayerBuilder::default()),
+                client_identity: None,
+                http_metrics: None,
+                error_classification: true,
+                validation: true,
+                slow_requests: None,
+                slo: None,
+                heavy_hitters: None,
+                in_flight: None,
+                method_counter: Some(JsonRpcMethodCounterLayer::default()),
+                method_histogram: Some(JsonRpcMethodHistogramLayer::default()),
+            },
+        }
+    }
+}
+
+impl Default for JsonRpcTelemetryLayer {
+    fn default() -> Self {
+        Self::builder().build()
+    }
+}
+
+pub struct JsonRpcTelemetryLayerBuilder {
+    layer: JsonRpcTelemetryLayer,
+}
+
+impl JsonRpcTelemetryLayerBuilder {
+    pub fn request_id(mut self, layer: RequestIdLayer) -> Self {
+        self.layer.request_id = Some(layer);
+        self
+    }
+
+    pub fn without_request_id(mut self) -> Self {
+        self.layer.request_id = None;
+        self
+    }
+
+    pub fn trace(mut self, builder: TraceLayerBuilder) -> Self {
+        self.layer.trace = Some(builder);
+        self
+    }
+
+    pub fn without_trace(mut self) -> Self {
+        self.layer.trace = None;
+        self
+    }
+
+    pub fn client_identity(mut self, layer: ClientIdentityLayer) -> Self {
+        self.layer.client_identity = Some(layer);
+        self
+    }
+
+    pub fn http_metrics(mut self, layer: HttpMetricsLayer) -> Self {
+        self.layer.http_metrics = Some(layer);
+        self
+    }
+
+    /// Stops reporting JSON-RPC errors as failures of the request span.
+    ///
+    /// Classification doesn't delay the response, frames are forwarded as they are produced, but
+    /// each response body is wrapped and its first 64 KiB are copied to read the error code at
+    /// its end. It only runs along the trace layer.
+    pub fn without_error_classification(mut self) -> Self {
+        self.layer.error_classification = false;
+        self
+    }
+
+    /// Forwards invalid requests, which the metric layers then count as `invalid`.
+    pub fn without_validation(mut self) -> Self {
+        self.layer.validation = false;
+        self
+    }
+
+    pub fn slow_requests(mut self, layer: SlowRequestLayer) -> Self {
+        self.layer.slow_requests = Some(layer);
+        self
+    }
+
+    pub fn slo(mut self, layer: SloLayer) -> Self {
+        self.layer.slo = Some(layer);
+        self
+    }
+
+    /// Keep a clone of the layer to serve its [`router`](HeavyHittersLayer::router).
+    pub fn heavy_hitters(mut self, layer: HeavyHittersLayer) -> Self {
+        self.layer.heavy_hitters = Some(layer);
+        self
+    }
+
+    pub fn in_flight(mut self, layer: JsonRpcMethodInFlightLayer) -> Self {
+        self.layer.in_flight = Some(layer);
+        self
+    }
+
+    pub fn method_counter(mut self, layer: JsonRpcMethodCounterLayer) -> Self {
+        self.layer.method_counter = Some(layer);
+        self
+    }
+
+    pub fn without_method_counter(mut self) -> Self {
+        self.layer.method_counter = None;
+        self
+    }
+
+    pub fn method_histogram(mut self, layer: JsonRpcMethodHistogramLayer) -> Self {
+        self.layer.method_histogram = Some(layer);
+        self
+    }
+
+    pub fn without_method_histogram(mut self) -> Self {
+        self.layer.method_histogram = None;
+        self
+    }
+
+    pub fn build(self) -> JsonRpcTelemetryLayer {
+        self.layer
+    }
+}
+
+/// Wraps `service` in `layer` when it is enabled.
+fn wrap<L>(service: JsonRpcTelemetryService, layer: Option<&L>) -> JsonRpcTelemetryService
+where
+    L: Layer<JsonRpcTelemetryService>,
+    L::Service: Service<Request<Body>, Response = Response, Error = Infallible>
+        + Clone
+        + Send
+        + Sync
+        + 'static,
+    <L::Service as Service<Request<Body>>>::Future: Send + 'static,
+{
+    match layer {
+        Some(layer) => BoxCloneSyncService::new(layer.layer(service)),
+        None => service,
+    }
+}
+
+impl<S> Layer<S> for JsonRpcTelemetryLayer
+where
+    S: Service<Request<Body>, Response = Response, Error = Infallible>
+        + Clone
+        + Send
+        + Sync
+        + 'static,
+    S::Future: Send + 'static,
+{
+    type Service = JsonRpcTelemetryService;
+
+    fn layer(&self, inner: S) -> Self::Service {
+        // Innermost first
+        let service = BoxCloneSyncService::new(inner);
+        let service = wrap(service, self.method_histogram.as_ref());
+        let service = wrap(service, self.method_counter.as_ref());
+        let service = wrap(service, self.in_flight.as_ref());
+        let service = wrap(service, self.heavy_hitters.as_ref());
+        let service = wrap(service, self.slo.as_ref());
+        let service = wrap(service, self.slow_requests.as_ref());
+        let service = wrap(service, self.validation.then_some(&RequestValidationLayer));
+        let service = wrap(service, self.http_metrics.as_ref());
+        let service = wrap(service, self.client_identity.as_ref());
+
+        let service = match self.trace {
+            Some(trace) if self.error_classification => BoxCloneSyncService::new(
+                trace
+                    .build()
+                    .layer(service)
+                    .map_response(|response| response.map(Body::new)),
+            ),
+            Some(trace) => BoxCloneSyncService::new(
+                trace
+                    .build_without_errors()
+                    .layer(service)
+                    .map_response(|response| response.map(Body::new)),
+            ),
+            None => service,
+        };
+
+        match &self.request_id {
+            Some(request_id) => BoxCloneSyncService::new(request_id.layer(service)),
+            None => service,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::middleware::SloObjective;
+    use axum::{Router, body::to_bytes, http::StatusCode, routing::post};
+    use futures_util::{StreamExt, stream};
+    use opentelemetry::metrics::MeterProvider;
+    use opentelemetry_sdk::metrics::{
+        InMemoryMetricExporter, PeriodicReader, SdkMeterProvider,
+        data::{AggregatedMetrics, MetricData},
+    };
+    use rpc::Request as RpcRequest;
+    use serde_json::{Value, json};
+    use std::time::Duration;
+
+    #[tokio::test]
+    async fn test_router() {
+        let router = Router::new()
+            .route(
+                "/",
+                post(|request: Request<Body>| async move {
+                    // Parsed once by the validation layer
+                    assert!(request.extensions().get::<RpcRequest>().is_some());
+                    axum::Json(json!({"jsonrpc": "2.0", "id": 7, "result": "0x1"}))
+                }),
+            )
+            .layer(
+                JsonRpcTelemetryLayer::builder()
+                    .client_identity(ClientIdentityLayer::default())
+                    .http_metrics(HttpMetricsLayer::default())
+                    .slow_requests(SlowRequestLayer::default())
+                    .in_flight(JsonRpcMethodInFlightLayer::default())
+                    .build(),
+            );
+
+        let request = Request::post("/")
+            .header("content-type", "application/json")
+            .body(Body::from(
+                r#"{"jsonrpc":"2.0","method":"eth_chainId","params":[],"id":7}"#,
+            ))
+            .unwrap();
+        let response = router.clone().oneshot(request).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert!(response.headers().contains_key("x-request-id"));
+        let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        let body: Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(body["result"], "0x1");
+
+        let request = Request::post("/")
+            .header("x-request-id", "incoming")
+            .body(Body::from("not json"))
+            .unwrap();
+        let response = router.oneshot(request).await.unwrap();
+        assert_eq!(response.headers()["x-request-id"], "incoming");
+        let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        let body: Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(body["error"]["code"], -32600);
+    }
+
+    #[tokio::test]
+    async fn test_streamed_response_timing() {
+        let exporter = InMemoryMetricExporter::default();
+        let provider = SdkMeterProvider::builder()
+            .with_reader(PeriodicReader::builder(exporter.clone()).build())
+            .build();
+        let histogram =
+            JsonRpcMethodHistogramLayer::builder().build_with_meter(&provider.meter("jsonrpc"));
+
+        let delay = Duration::from_millis(100);
+        let router = Router::new()
+            .route(
+                "/",
+                post(move || async move {
+                    let chunks = stream::iter([r#"{"jsonrpc":"2.0","id":1,"#, r#""result":[]}"#])
+                        .then(move |chunk| async move {
+                            if chunk.starts_with(r#""result""#) {
+                                tokio::time::sleep(delay).await;
+                            }
+                            Ok::<_, Infallible>(chunk)
+                        });
+                    Body::from_stream(chunks)
+                }),
+            )
+            .layer(
+                JsonRpcTelemetryLayer::builder()
+                    .http_metrics(HttpMetricsLayer::default())
+                    .slo(
+                        SloLayer::builder()
+                            .objective("eth_getlogs", SloObjective::availability(0.99).unwrap())
+                            .build(),
+                    )
+                    .method_histogram(histogram)
+                    .build(),
+            );
+
+        let request = Request::post("/")
+            .header("content-type", "application/json")
+            .body(Body::from(
+                r#"{"jsonrpc":"2.0","method":"eth_getLogs","params":[],"id":1}"#,
+            ))
+            .unwrap();
+        let response = router.oneshot(request).await.unwrap();
+        to_bytes(response.into_body(), usize::MAX).await.unwrap();
+
+        provider.force_flush().unwrap();
+        let metrics = exporter.get_finished_metrics().unwrap();
+        let seconds = |name: &str| {
+            metrics
+                .iter()
+                .flat_map(|metrics| metrics.scope_metrics())
+                .flat_map(|scope| scope.metrics())
+                .find(|metric| metric.name() == name)
+                .map(|metric| match metric.data() {
+                    AggregatedMetrics::F64(MetricData::Histogram(histogram)) => {
+                        histogram.data_points().next().unwrap().sum()
+                    }
+                    _ => unreachable!(),
+                })
+                .unwrap()
+        };
+
+        // Nothing outside the histogram holds the body back until its end
+        let first_byte = seconds("jsonrpc_method_time_to_first_byte");
+        let last_byte = seconds("jsonrpc_method_time_to_last_byte");
+        assert!(first_byte < delay.as_secs_f64(), "{first_byte}");
+        assert!(last_byte >= delay.as_secs_f64(), "{last_byte}");
+    }
+}
@@ -1,72 +1,313 @@
+use crate::middleware::{
+    ClientIdentity, JsonRpcErrorLayer,
+    classify::{JsonRpcFailureClass, ServerErrorClassifier},
+    client::client_address,
+};
 use axum::{
     body::{Body, Bytes},
-    http::{Request, Response},
+    http::{Request, Response, header, uri::Authority},
+};
+use std::{
+    sync::{
+        Arc,
+        atomic::{AtomicU64, Ordering},
+    },
+    time::Duration,
 };
-use std::time::Duration;
+use tower::layer::util::Stack;
 use tower_http::{
-    classify::{ServerErrorsAsFailures, ServerErrorsFailureClass, SharedClassifier},
+    classify::{ServerErrorsFailureClass, SharedClassifier},
     request_id::RequestId,
     trace::{DefaultOnEos, TraceLayer},
 };
-use tracing::{Span, error, info, info_span};
+use tracing::{Level, Span, event, field::Empty, info_span};
 
+/// Emits an event at a level only known at runtime.
+macro_rules! event_at {
+    ($level:expr, parent: $parent:expr, $($arguments:tt)+) => {
+        match $level {
+            Level::TRACE => event!(parent: $parent, Level::TRACE, $($arguments)+),
+            Level::DEBUG => event!(parent: $parent, Level::DEBUG, $($arguments)+),
+            Level::INFO => event!(parent: $parent, Level::INFO, $($arguments)+),
+            Level::WARN => event!(parent: $parent, Level::WARN, $($arguments)+),
+            Level::ERROR => event!(parent: $parent, Level::ERROR, $($arguments)+),
+        }
+    };
+}
+
+/// Logs every hook at info level and failures, including JSON-RPC errors, at error level.
+///
+/// JSON-RPC errors are reported by the [`JsonRpcErrorLayer`] running inside the trace layer.
 #[allow(clippy::type_complexity)]
-pub fn trace_layer() -> TraceLayer<
-    SharedClassifier<ServerErrorsAsFailures>,
-    impl Fn(&Request<Body>) -> Span + Clone,
-    impl Fn(&Request<Body>, &Span) + Clone,
-    impl Fn(&Response<Body>, Duration, &Span) + Clone,
-    impl Fn(&Bytes, Duration, &Span) + Clone,
-    DefaultOnEos,
-    impl Fn(ServerErrorsFailureClass, Duration, &Span) + Clone,
+pub fn trace_layer() -> Stack<
+    JsonRpcErrorLayer,
+    TraceLayer<
+        SharedClassifier<ServerErrorClassifier>,
+        impl Fn(&Request<Body>) -> Span + Clone,
+        impl Fn(&Request<Body>, &Span) + Clone,
+        impl Fn(&Response<Body>, Duration, &Span) + Clone,
+        impl Fn(&Bytes, Duration, &Span) + Clone,
+        DefaultOnEos,
+        impl Fn(JsonRpcFailureClass, Duration, &Span) + Clone,
+    >,
 > {
-    TraceLayer::new_for_http()
-        .make_span_with(|request: &Request<Body>| {
-            let trace_id = request
-                .extensions()
-                .get::<RequestId>()
-                .map(|id| id.header_value().to_str().unwrap_or("none").to_string())
-                .unwrap_or_else(|| "none".into());
-
-            info_span!(
-                "http_request",
-                trace_id,
-                method     = %request.method(),
-                uri        = %request.uri().path(),
-            )
-        })
-        .on_request(|_request: &Request<Body>, span: &Span| {
-            info!(parent: span, "Incoming request");
-        })
-        .on_body_chunk(|chunk: &Bytes, _latency: Duration, span: &Span| {
-            info!(parent: span, bytes = chunk.len(), "Body chunk");
-        })
-        .on_response(
-            |response: &Response<Body>, latency: Duration, span: &Span| {
-                info!(
-                    parent: span,
-                    status      = response.status().as_u16(),
-                    latency_ms  = latency.as_millis(),
-                    "Request Succeeded"
-                )
-            },
-        )
-        .on_failure(
-            |class: ServerErrorsFailureClass, latency: Duration, span: &Span| {
-                let (error, status) = match class {
-                    ServerErrorsFailureClass::StatusCode(code) => {
-                        ("N/A".to_string(), code.as_u16())
-                    }
-                    ServerErrorsFailureClass::Error(error) => (error.to_string(), 500),
+    TraceLayerBuilder::default().build()
+}
+
+/// Configures the level of each [`trace_layer`] hook and the sampling of access logs.
+///
+/// Failures are always logged, and so are responses slower than the slow threshold regardless of
+/// the sampling rate.
+#[derive(Clone, Copy, Debug)]
+pub struct TraceLayerBuilder {
+    request_level: Option<Level>,
+    body_chunk_level: Option<Level>,
+    response_level: Option<Level>,
+    failure_level: Level,
+    sample_responses: u64,
+    slow_threshold: Option<Duration>,
+}
+
+impl Default for TraceLayerBuilder {
+    fn default() -> Self {
+        Self {
+            request_level: Some(Level::INFO),
+            body_chunk_level: Some(Level::INFO),
+            response_level: Some(Level::INFO),
+            failure_level: Level::ERROR,
+            sample_responses: 1,
+            slow_threshold: None,
+        }
+    }
+}
+
+impl TraceLayerBuilder {
+    /// Level of the "Incoming request" log, `None` disables it.
+    pub fn request_level(mut self, level: Option<Level>) -> Self {
+        self.request_level = level;
+        self
+    }
+
+    /// Level of the "Body chunk" log, `None` disables it.
+    pub fn body_chunk_level(mut self, level: Option<Level>) -> Self {
+        self.body_chunk_level = level;
+        self
+    }
+
+    /// Level of the "Request Succeeded" access log, `None` disables it except for slow responses.
+    pub fn response_level(mut self, level: Option<Level>) -> Self {
+        self.response_level = level;
+        self
+    }
+
+    /// Level of the "Request Failed" log, including JSON-RPC errors.
+    pub fn failure_level(mut self, level: Level) -> Self {
+        self.failure_level = level;
+        self
+    }
+
+    /// Logs one in `n` successful responses, `0` is treated as `1`.
+    pub fn sample_responses(mut self, n: u64) -> Self {
+        self.sample_responses = n.max(1);
+        self
+    }
+
+    /// Responses at least this slow are always logged, at warn level if their level is lower.
+    pub fn slow_threshold(mut self, threshold: Duration) -> Self {
+        self.slow_threshold = Some(threshold);
+        self
+    }
+
+    /// Trace layer wrapping the [`JsonRpcErrorLayer`], which reports JSON-RPC errors on its span.
+    #[allow(clippy::type_complexity)]
+    pub fn build(
+        self,
+    ) -> Stack<
+        JsonRpcErrorLayer,
+        TraceLayer<
+            SharedClassifier<ServerErrorClassifier>,
+            impl Fn(&Request<Body>) -> Span + Clone,
+            impl Fn(&Request<Body>, &Span) + Clone,
+            impl Fn(&Response<Body>, Duration, &Span) + Clone,
+            impl Fn(&Bytes, Duration, &Span) + Clone,
+            DefaultOnEos,
+            impl Fn(JsonRpcFailureClass, Duration, &Span) + Clone,
+        >,
+    > {
+        let error_layer = JsonRpcErrorLayer::default().failure_level(self.failure_level);
+        Stack::new(error_layer, self.build_without_errors())
+    }
+
+    /// Trace layer only reporting server errors as failures.
+    #[allow(clippy::type_complexity)]
+    pub(crate) fn build_without_errors(
+        self,
+    ) -> TraceLayer<
+        SharedClassifier<ServerErrorClassifier>,
+        impl Fn(&Request<Body>) -> Span + Clone,
+        impl Fn(&Request<Body>, &Span) + Clone,
+        impl Fn(&Response<Body>, Duration, &Span) + Clone,
+        impl Fn(&Bytes, Duration, &Span) + Clone,
+        DefaultOnEos,
+        impl Fn(JsonRpcFailureClass, Duration, &Span) + Clone,
+    > {
+        let responses = Arc::new(AtomicU64::new(0));
+
+        TraceLayer::new(SharedClassifier::new(ServerErrorClassifier))
+            .make_span_with(|request: &Request<Body>| {
+                let trace_id = request
+                    .extensions()
+                    .get::<RequestId>()
+                    .map(|id| id.header_value().to_str().unwrap_or("none").to_string())
+                    .unwrap_or_else(|| "none".into());
+
+                let header_value = |name| {
+                    request
+                        .headers()
+                        .get(name)
+                        .and_then(|value| value.to_str().ok())
                 };
+                let host =
+                    header_value(header::HOST).and_then(|host| host.parse::<Authority>().ok());
+                let server_address = request.uri().host().or(host.as_ref().map(Authority::host));
+
+                // `client` is recorded by `ClientIdentityLayer` when it runs inside this layer, the
+                // response fields once it is known
+                let span = info_span!(
+                    "http_request",
+                    trace_id,
+                    method     = %request.method(),
+                    uri        = %request.uri().path(),
+                    client     = Empty,
+                    otel.kind  = "server",
+                    http.request.method = %request.method(),
+                    url.path   = request.uri().path(),
+                    url.scheme = request.uri().scheme_str().unwrap_or("http"),
+                    server.address = server_address,
+                    user_agent.original = header_value(header::USER_AGENT),
+                    client.address = client_address(request).map(tracing::field::display),
+                    http.response.status_code = Empty,
+                    otel.status_code = Empty,
+                    otel.status_description = Empty,
+                    rpc.jsonrpc.error_code = Empty,
+                );
+                if let Some(identity) = request.extensions().get::<ClientIdentity>() {
+                    span.record("client", identity.id());
+                }
+                span
+            })
+            .on_request(move |_request: &Request<Body>, span: &Span| {
+                if let Some(level) = self.request_level {
+                    event_at!(level, parent: span, "Incoming request");
+                }
+            })
+            .on_body_chunk(move |chunk: &Bytes, _latency: Duration, span: &Span| {
+                if let Some(level) = self.body_chunk_level {
+                    event_at!(level, parent: span, bytes = chunk.len(), "Body chunk");
+                }
+            })
+            .on_response(
+                move |response: &Response<Body>, latency: Duration, span: &Span| {
+                    span.record("http.response.status_code", response.status().as_u16());
+
+                    let Some(level) = self.access_log_level(&responses, latency) else {
+                        return;
+                    };
+
+                    event_at!(
+                        level,
+                        parent: span,
+                        status      = response.status().as_u16(),
+                        latency_ms  = latency.as_millis(),
+                        "Request Succeeded"
+                    )
+                },
+            )
+            .on_failure(
+                move |class: JsonRpcFailureClass, latency: Duration, span: &Span| {
+                    record_failure(self.failure_level, class, latency, span)
+                },
+            )
+    }
+
+    /// Level of the access log of a response, `None` when it is disabled or sampled out.
+    fn access_log_level(&self, responses: &AtomicU64, latency: Duration) -> Option<Level> {
+        if self
+            .slow_threshold
+            .is_some_and(|threshold| latency >= threshold)
+        {
+            // More verbose levels compare greater, log slow responses at warn at least
+            return Some(
+                self.response_level
+                    .map_or(Level::WARN, |level| level.min(Level::WARN)),
+            );
+        }
+
+        let level = self.response_level?;
+        let count = responses.fetch_add(1, Ordering::Relaxed);
+        count.is_multiple_of(self.sample_responses).then_some(level)
+    }
+}
+
+/// Records the failure on the span and logs it, shared with [`JsonRpcErrorLayer`] which reports
+/// JSON-RPC errors at the end of the response body.
+pub(crate) fn record_failure(
+    level: Level,
+    class: JsonRpcFailureClass,
+    latency: Duration,
+    span: &Span,
+) {
+    let (error, status) = match class {
+        JsonRpcFailureClass::Http(ServerErrorsFailureClass::StatusCode(code)) => {
+            ("N/A".to_string(), code.as_u16())
+        }
+        JsonRpcFailureClass::Http(ServerErrorsFailureClass::Error(error)) => {
+            span.record("otel.status_description", error.as_str());
+            (error, 500)
+        }
+        JsonRpcFailureClass::JsonRpc(error) => {
+            span.record("rpc.jsonrpc.error_code", error.code);
+            span.record("otel.status_description", class.to_string());
+            (class.to_string(), 200)
+        }
+    };
+    span.record("otel.status_code", "ERROR");
+
+    event_at!(
+        level,
+        parent: span,
+        error,
+        status,
+        latency_ms  = latency.as_millis(),
+        "Request Failed"
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_response_sampling() {
+        let builder = TraceLayerBuilder::default()
+            .sample_responses(3)
+            .slow_threshold(Duration::from_secs(1));
+        let responses = AtomicU64::new(0);
+        let fast = Duration::from_millis(10);
+
+        let logged = (0..6)
+            .filter(|_| builder.access_log_level(&responses, fast).is_some())
+            .count();
+        assert_eq!(logged, 2);
+
+        let slow = builder.access_log_level(&responses, Duration::from_secs(2));
+        assert_eq!(slow, Some(Level::WARN));
 
-                error!(
-                    parent: span,
-                    error,
-                    status,
-                    latency_ms  = latency.as_millis(),
-                    "Request Failed"
-                )
-            },
-        )
+        let disabled = builder.response_level(None);
+        assert_eq!(disabled.access_log_level(&responses, fast), None);
+        assert_eq!(
+            disabled.access_log_level(&responses, Duration::from_secs(2)),
+            Some(Level::WARN)
+        );
+    }
 }
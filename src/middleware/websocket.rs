@@ -0,0 +1,351 @@
+//! Instrumentation of JSON-RPC sessions over WebSocket.
+//!
+//! The HTTP layers see a single upgrade request for the whole connection, so sessions are
+//! instrumented from the socket handler instead: create a [`WebSocketSession`] per connection,
+//! run the handler within its [`span`](WebSocketSession::span) and pass it every text frame. The
+//! [`ClientIdentity`] extension of the upgrade request can be recorded on the span with
+//! [`WebSocketSession::client`].
+//!
+//! Calls are counted in `jsonrpc_ws_method_calls` and their latency recorded in
+//! `jsonrpc_ws_method_latency` once the response with the same id is sent. Calls left without a
+//! response for [`PENDING_TIMEOUT`] are counted in `jsonrpc_ws_method_timeouts` and no longer
+//! tracked. Open connections and subscriptions are tracked by `jsonrpc_ws_connections` and
+//! `jsonrpc_ws_subscriptions`, and notifications are counted per subscription type in
+//! `jsonrpc_ws_notifications`.
+
+use crate::middleware::{ClientIdentity, histogram::DEFAULT_LATENCY_BUCKETS};
+use opentelemetry::{
+    KeyValue, global,
+    metrics::{Counter, Histogram, UpDownCounter},
+};
+use serde::{Deserialize, de::IgnoredAny};
+use serde_json::value::RawValue;
+use std::{
+    collections::HashMap,
+    time::{Duration, Instant},
+};
+use tracing::{Span, field::Empty, info, info_span};
+
+/// Upper bound of calls awaiting a response per connection, later calls aren't timed
+const MAX_PENDING: usize = 10_000;
+
+/// Calls without a response after this long are counted as timed out.
+pub const PENDING_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// Minimum interval between two scans for timed out calls
+const EXPIRE_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Subscription type of notifications for unknown subscription ids
+const UNKNOWN_SUBSCRIPTION: &str = "unknown";
+
+#[derive(Clone)]
+pub struct WebSocketTelemetry {
+    calls: Counter<u64>,
+    latency: Histogram<f64>,
+    connections: UpDownCounter<i64>,
+    subscriptions: UpDownCounter<i64>,
+    notifications: Counter<u64>,
+    timeouts: Counter<u64>,
+}
+
+impl Default for WebSocketTelemetry {
+    fn default() -> Self {
+        let meter = global::meter("jsonrpc");
+        let calls = meter.u64_counter("jsonrpc_ws_method_calls").build();
+        let latency = meter
+            .f64_histogram("jsonrpc_ws_method_latency")
+            .with_unit("s")
+            .with_boundaries(DEFAULT_LATENCY_BUCKETS.to_vec())
+            .build();
+        let connections = meter.i64_up_down_counter("jsonrpc_ws_connections").build();
+        let subscriptions = meter
+            .i64_up_down_counter("jsonrpc_ws_subscriptions")
+            .build();
+        let notifications = meter.u64_counter("jsonrpc_ws_notifications").build();
+        let timeouts = meter.u64_counter("jsonrpc_ws_method_timeouts").build();
+        Self {
+            calls,
+            latency,
+            connections,
+            subscriptions,
+            notifications,
+            timeouts,
+        }
+    }
+}
+
+impl WebSocketTelemetry {
+    /// Starts instrumenting a new connection, closed when the session is dropped.
+    pub fn session(&self) -> WebSocketSession {
+        self.connections.add(1, &[]);
+        WebSocketSession {
+            telemetry: self.clone(),
+            span: info_span!("ws_connection", otel.kind = "server", client = Empty),
+            started: Instant::now(),
+            messages: 0,
+            expired: Instant::now(),
+            pending: HashMap::new(),
+            subscriptions: HashMap::new(),
+        }
+    }
+}
+
+/// Call awaiting its response
+struct Pending {
+    method: String,
+    started: Instant,
+    /// Subscription type of `eth_subscribe`, subscription id of `eth_unsubscribe`
+    subscription: Option<String>,
+}
+
+/// JSON-RPC request, response or notification, fields not needed for telemetry are skipped.
+#[derive(Deserialize)]
+struct Message<'a> {
+    #[serde(borrow, default)]
+    id: Option<&'a RawValue>,
+    #[serde(default)]
+    method: Option<String>,
+    #[serde(borrow, default)]
+    params: Option<&'a RawValue>,
+    #[serde(borrow, default)]
+    result: Option<&'a RawValue>,
+    #[serde(default)]
+    error: Option<IgnoredAny>,
+}
+
+#[derive(Deserialize)]
+struct Notification {
+    subscription: String,
+}
+
+/// Messages of a single or batch frame, empty for unparseable frames.
+///
+/// Raw values can't be buffered by an untagged enum, so batches are told apart by their bracket.
+fn parse(text: &str) -> Vec<Message<'_>> {
+    if text.trim_start().starts_with('[') {
+        serde_json::from_str(text).unwrap_or_default()
+    } else {
+        serde_json::from_str(text)
+            .map(|message| vec![message])
+            .unwrap_or_default()
+    }
+}
+
+/// Telemetry of one WebSocket connection.
+pub struct WebSocketSession {
+    telemetry: WebSocketTelemetry,
+    span: Span,
+    started: Instant,
+    messages: u64,
+    /// Last scan for timed out calls
+    expired: Instant,
+    pending: HashMap<String, Pending>,
+    /// Subscription type by subscription id
+    subscriptions: HashMap<String, String>,
+}
+
+impl WebSocketSession {
+    /// Span of the connection, the socket handler should be instrumented with it.
+    pub fn span(&self) -> &Span {
+        &self.span
+    }
+
+    /// Records the client of the upgrade request on the span.
+    pub fn client(&self, identity: &ClientIdentity) {
+        self.span.record("client", identity.id());
+    }
+
+    /// Records a text frame received from the client.
+    pub fn client_message(&mut self, text: &str) {
+        let now = Instant::now();
+        if now.duration_since(self.expired) >= EXPIRE_INTERVAL || self.pending.len() >= MAX_PENDING
+        {
+            self.expire(now);
+        }
+
+        for message in parse(text) {
+            self.messages += 1;
+
+            let Some(method) = message.method.map(|method| method.to_lowercase()) else {
+                continue;
+            };
+            self.telemetry
+                .calls
+                .add(1, &[KeyValue::new("method", method.clone())]);
+
+            // Notifications from the client have no id and get no response
+            let Some(id) = message.id else {
+                continue;
+            };
+            if self.pending.len() >= MAX_PENDING {
+                continue;
+            }
+
+            let subscription = match method.as_str() {
+                "eth_subscribe" => first_param(message.params).map(|kind| subscription_type(&kind)),
+                "eth_unsubscribe" => first_param(message.params),
+                _ => None,
+            };
+
+            self.pending.insert(
+                id.get().to_string(),
+                Pending {
+                    method,
+                    started: now,
+                    subscription,
+                },
+            );
+        }
+    }
+
+    /// Stops tracking calls without a response for [`PENDING_TIMEOUT`] and counts them.
+    fn expire(&mut self, now: Instant) {
+        self.expired = now;
+        let timeouts = &self.telemetry.timeouts;
+        self.pending.retain(|_, pending| {
+            let expired = now.duration_since(pending.started) >= PENDING_TIMEOUT;
+            if expired {
+                timeouts.add(1, &[KeyValue::new("method", pending.method.clone())]);
+            }
+            !expired
+        });
+    }
+
+    /// Records a text frame sent to the client.
+    pub fn server_message(&mut self, text: &str) {
+        for message in parse(text) {
+            if message.method.as_deref() == Some("eth_subscription") {
+                self.notification(message.params);
+                continue;
+            }
+
+            let Some(pending) = message.id.and_then(|id| self.pending.remove(id.get())) else {
+                continue;
+            };
+            self.response(pending, message.result, message.error.is_some());
+        }
+    }
+
+    fn notification(&self, params: Option<&RawValue>) {
+        let subscription = params
+            .and_then(|params| serde_json::from_str::<Notification>(params.get()).ok())
+            .and_then(|notification| self.subscriptions.get(&notification.subscription))
+            .map_or(UNKNOWN_SUBSCRIPTION, String::as_str);
+
+        self.telemetry.notifications.add(
+            1,
+            &[KeyValue::new("subscription", subscription.to_string())],
+        );
+    }
+
+    fn response(&mut self, pending: Pending, result: Option<&RawValue>, error: bool) {
+        self.telemetry.latency.record(
+            pending.started.elapsed().as_secs_f64(),
+            &[KeyValue::new("method", pending.method.clone())],
+        );
+
+        if error {
+            return;
+        }
+
+        match (pending.method.as_str(), pending.subscription) {
+            ("eth_subscribe", Some(kind)) => {
+                let Some(id) = result.and_then(|result| serde_json::from_str(result.get()).ok())
+                else {
+                    return;
+                };
+                self.telemetry
+                    .subscriptions
+                    .add(1, &[KeyValue::new("subscription", kind.clone())]);
+                self.subscriptions.insert(id, kind);
+            }
+            ("eth_unsubscribe", Some(id)) => {
+                if let Some(kind) = self.subscriptions.remove(&id) {
+                    self.telemetry
+                        .subscriptions
+                        .add(-1, &[KeyValue::new("subscription", kind)]);
+                }
+            }
+            _ => {}
+        }
+    }
+}
+
+/// First positional parameter, the subscription type or id
+fn first_param(params: Option<&RawValue>) -> Option<String> {
+    let params: Vec<&RawValue> = serde_json::from_str(params?.get()).ok()?;
+    serde_json::from_str(params.first()?.get()).ok()
+}
+
+/// Known subscription types are reported as is, anything else as `other` to bound the cardinality.
+fn subscription_type(kind: &str) -> String {
+    match kind {
+        "newHeads" | "logs" | "newPendingTransactions" | "syncing" => kind.to_string(),
+        _ => "other".to_string(),
+    }
+}
+
+impl Drop for WebSocketSession {
+    fn drop(&mut self) {
+        self.telemetry.connections.add(-1, &[]);
+        for kind in self.subscriptions.values() {
+            self.telemetry
+                .subscriptions
+                .add(-1, &[KeyValue::new("subscription", kind.clone())]);
+        }
+
+        info!(
+            parent: &self.span,
+            messages = self.messages,
+            subscriptions = self.subscriptions.len(),
+            duration_ms = self.started.elapsed().as_millis(),
+            "WebSocket connection closed"
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_session() {
+        let mut session = WebSocketTelemetry::default().session();
+
+        session.client_message(
+            r#"[{"jsonrpc":"2.0","id":1,"method":"eth_subscribe","params":["newHeads"]},
+                {"jsonrpc":"2.0","id":"a","method":"eth_subscribe","params":["logs",{"address":"0x0"}]},
+                {"jsonrpc":"2.0","id":2,"method":"eth_blockNumber","params":[]}]"#,
+        );
+        assert_eq!(session.pending.len(), 3);
+        assert_eq!(
+            session.pending["\"a\""].subscription.as_deref(),
+            Some("logs")
+        );
+
+        session.server_message(r#"{"jsonrpc":"2.0","id":1,"result":"0xaa"}"#);
+        session.server_message(r#"{"jsonrpc":"2.0","id":"a","result":"0xbb"}"#);
+        session.server_message(r#"{"jsonrpc":"2.0","id":2,"result":"0x10"}"#);
+        assert!(session.pending.is_empty());
+        assert_eq!(session.subscriptions["0xaa"], "newHeads");
+        assert_eq!(session.subscriptions["0xbb"], "logs");
+
+        session.server_message(
+            r#"{"jsonrpc":"2.0","method":"eth_subscription","params":{"subscription":"0xaa","result":{}}}"#,
+        );
+
+        session.client_message(
+            r#"{"jsonrpc":"2.0","id":3,"method":"eth_unsubscribe","params":["0xaa"]}"#,
+        );
+        session.server_message(r#"{"jsonrpc":"2.0","id":3,"result":true}"#);
+        assert_eq!(session.subscriptions.len(), 1);
+        assert_eq!(session.messages, 4);
+
+        session.client_message(r#"{"jsonrpc":"2.0","id":4,"method":"eth_call","params":[]}"#);
+        session.client_message(r#"{"jsonrpc":"2.0","id":5,"method":"eth_call","params":[]}"#);
+        let now = Instant::now();
+        session.pending.get_mut("4").unwrap().started = now - PENDING_TIMEOUT;
+        session.expire(now);
+        assert_eq!(session.pending.keys().collect::<Vec<_>>(), ["5"]);
+    }
+}
@@ -0,0 +1,209 @@
+//! Resource detectors for the host, process, container and Kubernetes environment.
+//!
+//! Detected attributes have the lowest precedence: `OTEL_RESOURCE_ATTRIBUTES` overrides them and
+//! the values configured on [`crate::TelemetryBuilder`] override both.
+
+use crate::build_info::RUSTC_VERSION;
+use opentelemetry::KeyValue;
+use opentelemetry_sdk::resource::{Resource, ResourceDetector};
+use std::{env, fs, process};
+use uuid::Uuid;
+
+/// Detectors run by [`crate::Telemetry`] before the SDK's own ones.
+pub(crate) fn detectors() -> Vec<Box<dyn ResourceDetector>> {
+    vec![
+        Box::new(HostResourceDetector),
+        Box::new(ProcessResourceDetector),
+        Box::new(ContainerResourceDetector),
+        Box::new(KubernetesResourceDetector),
+    ]
+}
+
+/// Detects `host.name`, `host.arch` and `os.type`.
+pub(crate) struct HostResourceDetector;
+
+impl ResourceDetector for HostResourceDetector {
+    fn detect(&self) -> Resource {
+        let mut attributes = vec![
+            KeyValue::new("host.arch", env::consts::ARCH),
+            KeyValue::new("os.type", env::consts::OS),
+        ];
+
+        let host_name = fs::read_to_string("/proc/sys/kernel/hostname")
+            .ok()
+            .or_else(|| env::var("HOSTNAME").ok())
+            .map(|name| name.trim().to_string())
+            .filter(|name| !name.is_empty());
+
+        if let Some(host_name) = host_name {
+            attributes.push(KeyValue::new("host.name", host_name));
+        }
+
+        Resource::builder_empty()
+            .with_attributes(attributes)
+            .build()
+    }
+}
+
+/// Detects `process.*` attributes and a random `service.instance.id` for this process.
+pub(crate) struct ProcessResourceDetector;
+
+impl ResourceDetector for ProcessResourceDetector {
+    fn detect(&self) -> Resource {
+        let description = RUSTC_VERSION;
+        let version = description.split_whitespace().nth(1).unwrap_or(description);
+
+        let mut attributes = vec![
+            KeyValue::new("process.pid", process::id() as i64),
+            KeyValue::new("process.runtime.name", "rustc"),
+            KeyValue::new("process.runtime.version", version),
+            KeyValue::new("process.runtime.description", description),
+            KeyValue::new("service.instance.id", Uuid::new_v4().to_string()),
+        ];
+
+        if let Some(name) = env::current_exe()
+            .ok()
+            .and_then(|path| Some(path.file_name()?.to_string_lossy().into_owned()))
+        {
+            attributes.push(KeyValue::new("process.executable.name", name));
+        }
+
+        Resource::builder_empty()
+            .with_attributes(attributes)
+            .build()
+    }
+}
+
+/// Detects `container.id` from the cgroup (v1) or mount (v2) information of the process.
+pub(crate) struct ContainerResourceDetector;
+
+impl ResourceDetector for ContainerResourceDetector {
+    fn detect(&self) -> Resource {
+        let container_id = fs::read_to_string("/proc/self/cgroup")
+            .ok()
+            .and_then(|cgroup| container_id_from_cgroup(&cgroup))
+            .or_else(|| {
+                fs::read_to_string("/proc/self/mountinfo")
+                    .ok()
+                    .and_then(|mountinfo| container_id_from_mountinfo(&mountinfo))
+            });
+
+        let builder = Resource::builder_empty();
+        match container_id {
+            Some(id) => builder.with_attribute(KeyValue::new("container.id", id)),
+            None => builder,
+        }
+        .build()
+    }
+}
+
+/// Detects `k8s.*` attributes from the environment variables set through the downward API.
+pub(crate) struct KubernetesResourceDetector;
+
+impl KubernetesResourceDetector {
+    /// Each attribute is read from the first set variable
+    const VARIABLES: &[(&str, &[&str])] = &[
+        ("k8s.pod.name", &["K8S_POD_NAME", "POD_NAME"]),
+        ("k8s.pod.uid", &["K8S_POD_UID", "POD_UID"]),
+        (
+            "k8s.namespace.name",
+            &["K8S_NAMESPACE_NAME", "POD_NAMESPACE"],
+        ),
+        ("k8s.node.name", &["K8S_NODE_NAME", "NODE_NAME"]),
+    ];
+}
+
+impl ResourceDetector for KubernetesResourceDetector {
+    fn detect(&self) -> Resource {
+        let attributes = Self::VARIABLES.iter().filter_map(|(key, variables)| {
+            variables
+                .iter()
+                .find_map(|variable| env::var(variable).ok().filter(|value| !value.is_empty()))
+                .map(|value| KeyValue::new(*key, value))
+        });
+
+        Resource::builder_empty()
+            .with_attributes(attributes)
+            .build()
+    }
+}
+
+fn is_container_id(id: &str) -> bool {
+    id.len() == 64 && id.bytes().all(|byte| byte.is_ascii_hexdigit())
+}
+
+/// Extracts the id from cgroup v1 paths such as `/docker/<id>` or `/kubepods/.../cri-containerd-<id>.scope`.
+fn container_id_from_cgroup(cgroup: &str) -> Option<String> {
+    cgroup.lines().find_map(|line| {
+        let segment = line.rsplit('/').next()?;
+        let segment = segment.strip_suffix(".scope").unwrap_or(segment);
+        let id = segment.rsplit('-').next()?;
+        is_container_id(id).then(|| id.to_string())
+    })
+}
+
+/// Directories whose next segment is the container id, of docker and containerd respectively
+const CONTAINER_DIRS: &[&str] = &["/containers/", "/sandboxes/"];
+
+/// Extracts the id from cgroup v2 mounts such as `/var/lib/docker/containers/<id>/hostname`.
+///
+/// Only the mount root is considered, overlay options of the root mount also contain 64 hex digit
+/// layer and snapshot ids which aren't the container id.
+fn container_id_from_mountinfo(mountinfo: &str) -> Option<String> {
+    mountinfo.lines().find_map(|line| {
+        let root = line.split_whitespace().nth(3)?;
+        CONTAINER_DIRS.iter().find_map(|dir| {
+            let (_, rest) = root.split_once(dir)?;
+            let id = rest.split('/').next()?;
+            is_container_id(id).then(|| id.to_string())
+        })
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ID: &str = "3f2b8a6c1d0e4f5a9b7c2d1e0f3a4b5c6d7e8f9a0b1c2d3e4f5a6b7c8d9e0f1a";
+
+    #[test]
+    fn test_container_id_from_cgroup() {
+        let docker = format!("12:memory:/docker/{ID}\n0::/\n");
+        assert_eq!(container_id_from_cgroup(&docker).as_deref(), Some(ID));
+
+        let containerd =
+            format!("0::/kubepods.slice/kubepods-pod1.slice/cri-containerd-{ID}.scope\n");
+        assert_eq!(container_id_from_cgroup(&containerd).as_deref(), Some(ID));
+
+        assert_eq!(container_id_from_cgroup("0::/init.scope\n"), None);
+    }
+
+    #[test]
+    fn test_container_id_from_mountinfo() {
+        let mountinfo = format!(
+            "1 0 0:1 / / rw - overlay overlay rw\n\
+             2 1 8:1 /var/lib/docker/containers/{ID}/hostname /etc/hostname rw - ext4 /dev/sda1 rw\n"
+        );
+        assert_eq!(container_id_from_mountinfo(&mountinfo).as_deref(), Some(ID));
+
+        let layer = "a".repeat(64);
+        let overlay = format!(
+            "1 0 0:1 / / rw - overlay overlay rw,\
+             lowerdir=/var/lib/docker/overlay2/l/{layer}:/var/lib/docker/overlay2/{layer}/diff,\
+             upperdir=/var/lib/docker/overlay2/{layer}/diff\n\
+             2 1 8:1 /var/lib/containerd/io.containerd.grpc.v1.cri/sandboxes/{ID}/hostname \
+             /etc/hostname rw - ext4 /dev/sda1 rw\n"
+        );
+        assert_eq!(container_id_from_mountinfo(&overlay).as_deref(), Some(ID));
+
+        let overlay = format!(
+            "1 0 0:1 / / rw - overlay overlay rw,lowerdir=/l/{layer},upperdir=/u/{layer}/diff\n"
+        );
+        assert_eq!(container_id_from_mountinfo(&overlay), None);
+
+        assert_eq!(
+            container_id_from_mountinfo("1 0 0:1 / / rw - overlay overlay rw\n"),
+            None
+        );
+    }
+}
@@ -0,0 +1,238 @@
+//! Rolling file sink for JSON logs.
+//!
+//! Logs are handed to a background worker through a non-blocking writer so that slow disks never
+//! stall the request path. Time based rotation is delegated to `tracing-appender`, size based
+//! rotation is implemented by [`SizeRollingWriter`].
+
+use eyre::{Result, WrapErr};
+use std::{
+    fs::{self, File, OpenOptions},
+    io::{self, Write},
+    path::{Path, PathBuf},
+};
+use tracing_appender::{
+    non_blocking::{NonBlocking, WorkerGuard},
+    rolling::{self, RollingFileAppender},
+};
+
+/// When the active log file is closed and a new one is started.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Rotation {
+    Hourly,
+    Daily,
+    /// Rotate once the active file grows past the given number of bytes.
+    Size(u64),
+    Never,
+}
+
+/// Configuration of the optional file sink installed next to the console logger.
+#[derive(Clone, Debug)]
+pub struct FileLogConfig {
+    directory: PathBuf,
+    prefix: String,
+    rotation: Rotation,
+    max_files: Option<usize>,
+}
+
+impl FileLogConfig {
+    /// Writes `<prefix>.log` files into `directory`, rotating daily and keeping every file.
+    pub fn new(directory: impl Into<PathBuf>, prefix: impl Into<String>) -> Self {
+        Self {
+            directory: directory.into(),
+            prefix: prefix.into(),
+            rotation: Rotation::Daily,
+            max_files: None,
+        }
+    }
+
+    pub fn with_rotation(mut self, rotation: Rotation) -> Self {
+        self.rotation = rotation;
+        self
+    }
+
+    /// Keeps at most `max_files` log files, deleting the oldest ones on rotation.
+    pub fn with_max_files(mut self, max_files: usize) -> Self {
+        self.max_files = Some(max_files);
+        self
+    }
+
+    /// Builds the non-blocking writer; the returned guard flushes pending lines when dropped.
+    pub(crate) fn writer(&self) -> Result<(NonBlocking, WorkerGuard)> {
+        fs::create_dir_all(&self.directory).wrap_err_with(|| {
+            format!(
+                "Failed to create log directory {}",
+                self.directory.display()
+            )
+        })?;
+
+        let rotation = match self.rotation {
+            Rotation::Hourly => rolling::Rotation::HOURLY,
+            Rotation::Daily => rolling::Rotation::DAILY,
+            Rotation::Never => rolling::Rotation::NEVER,
+            Rotation::Size(max_bytes) => {
+                let writer = SizeRollingWriter::new(
+                    &self.directory,
+                    &self.prefix,
+                    max_bytes,
+                    self.max_files,
+                )
+                .wrap_err("Failed to open log file")?;
+
+                return Ok(tracing_appender::non_blocking(writer));
+            }
+        };
+
+        let mut builder = RollingFileAppender::builder()
+            .rotation(rotation)
+            .filename_prefix(&self.prefix)
+            .filename_suffix("log");
+
+        if let Some(max_files) = self.max_files {
+            builder = builder.max_log_files(max_files);
+        }
+
+        let appender = builder
+            .build(&self.directory)
+            .wrap_err("Failed to create rolling file appender")?;
+
+        Ok(tracing_appender::non_blocking(appender))
+    }
+}
+
+/// Writes to `<prefix>.log` and shifts it to `<prefix>.log.1`, `<prefix>.log.2`, ... once it
+/// exceeds `max_bytes`.
+///
+/// Rotation only happens between writes, and the non-blocking worker writes whole lines, so a
+/// single JSON line is never split across two files.
+struct SizeRollingWriter {
+    directory: PathBuf,
+    prefix: String,
+    max_bytes: u64,
+    max_files: Option<usize>,
+    file: File,
+    written: u64,
+}
+
+impl SizeRollingWriter {
+    fn new(
+        directory: &Path,
+        prefix: &str,
+        max_bytes: u64,
+        max_files: Option<usize>,
+    ) -> io::Result<Self> {
+        let path = directory.join(format!("{prefix}.log"));
+        let file = OpenOptions::new().create(true).append(true).open(&path)?;
+        let written = file.metadata()?.len();
+
+        Ok(Self {
+            directory: directory.to_path_buf(),
+            prefix: prefix.to_string(),
+            max_bytes,
+            max_files,
+            file,
+            written,
+        })
+    }
+
+    fn path(&self, index: usize) -> PathBuf {
+        match index {
+            0 => self.directory.join(format!("{}.log", self.prefix)),
+            index => self.directory.join(format!("{}.log.{index}", self.prefix)),
+        }
+    }
+
+    fn rotate(&mut self) -> io::Result<()> {
+        self.file.flush()?;
+
+        // `max_files` counts the active file, so the oldest rotated file has index `max_files - 1`
+        let oldest = match self.max_files {
+            Some(max_files) => max_files.saturating_sub(1),
+            None => (1..).find(|index| !self.path(*index).exists()).unwrap_or(1),
+        };
+
+        if oldest == 0 {
+            fs::remove_file(self.path(0))?;
+        } else {
+            let _ = fs::remove_file(self.path(oldest));
+            for index in (0..oldest).rev() {
+                if self.path(index).exists() {
+                    fs::rename(self.path(index), self.path(index + 1))?;
+                }
+            }
+        }
+
+        self.file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(self.path(0))?;
+        self.written = 0;
+
+        Ok(())
+    }
+}
+
+impl Write for SizeRollingWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if self.written > 0 && self.written + buf.len() as u64 > self.max_bytes {
+            self.rotate()?;
+        }
+
+        let written = self.file.write(buf)?;
+        self.written += written as u64;
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.file.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{env, process};
+
+    fn test_directory(name: &str) -> PathBuf {
+        let directory = env::temp_dir().join(format!("telemetry-{name}-{}", process::id()));
+        let _ = fs::remove_dir_all(&directory);
+        fs::create_dir_all(&directory).unwrap();
+        directory
+    }
+
+    #[test]
+    fn test_size_rotation() {
+        let directory = test_directory("size-rotation");
+        let mut writer = SizeRollingWriter::new(&directory, "app", 10, None).unwrap();
+
+        writer.write_all(b"0123456789").unwrap();
+        writer.write_all(b"abcdef").unwrap();
+        writer.write_all(b"ghijkl").unwrap();
+        writer.flush().unwrap();
+
+        assert_eq!(fs::read(directory.join("app.log")).unwrap(), b"ghijkl");
+        assert_eq!(fs::read(directory.join("app.log.1")).unwrap(), b"abcdef");
+        assert_eq!(
+            fs::read(directory.join("app.log.2")).unwrap(),
+            b"0123456789"
+        );
+
+        fs::remove_dir_all(directory).unwrap();
+    }
+
+    #[test]
+    fn test_size_rotation_retention() {
+        let directory = test_directory("size-retention");
+        let mut writer = SizeRollingWriter::new(&directory, "app", 4, Some(2)).unwrap();
+
+        for line in [b"aaaa", b"bbbb", b"cccc", b"dddd"] {
+            writer.write_all(line).unwrap();
+        }
+        writer.flush().unwrap();
+
+        assert_eq!(fs::read(directory.join("app.log")).unwrap(), b"dddd");
+        assert_eq!(fs::read(directory.join("app.log.1")).unwrap(), b"cccc");
+        assert!(!directory.join("app.log.2").exists());
+
+        fs::remove_dir_all(directory).unwrap();
+    }
+}
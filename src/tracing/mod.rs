@@ -0,0 +1,142 @@
+//! Tracing module for OpenTelemetry integration.
+
+mod file;
+
+use crate::{
+    TelemetryLayer,
+    export::{InstrumentedExporter, is_suppressed},
+};
+use eyre::{Result, eyre};
+pub use file::{FileLogConfig, Rotation};
+use fmt::{
+    format::{DefaultFields, Format, Json, JsonFields},
+    layer,
+};
+use global::{set_text_map_propagator, set_tracer_provider};
+use opentelemetry::{global, trace::TracerProvider};
+use opentelemetry_appender_tracing::layer::OpenTelemetryTracingBridge;
+use opentelemetry_otlp::{LogExporter, SpanExporter};
+use opentelemetry_sdk::{
+    Resource,
+    logs::SdkLoggerProvider,
+    propagation::TraceContextPropagator,
+    trace::{SdkTracerProvider, TracerProviderBuilder},
+};
+use tracing::Subscriber;
+use tracing_appender::non_blocking::WorkerGuard;
+use tracing_opentelemetry::OpenTelemetryLayer;
+use tracing_subscriber::{
+    EnvFilter, Layer,
+    filter::{FilterExt, filter_fn},
+    fmt,
+    registry::LookupSpan,
+};
+
+/// Filters use the [`EnvFilter`] directive syntax, unset filters fall back to `RUST_LOG` or `info`.
+#[derive(Default)]
+pub struct TracingConfig {
+    pub file: Option<FileLogConfig>,
+    /// Applies to the console logger and the file sink.
+    pub console_filter: Option<String>,
+    pub otlp_logs_filter: Option<String>,
+    pub otlp_traces_filter: Option<String>,
+}
+
+pub struct Tracing {
+    tracer_provider: SdkTracerProvider,
+    _file_guard: Option<WorkerGuard>,
+}
+
+impl Tracing {
+    /// Builds the OTLP and console layers without installing them or touching global state, see
+    /// [`Tracing::set_global`].
+    pub fn layer<S>(resource: Resource, config: TracingConfig) -> Result<(Self, TelemetryLayer<S>)>
+    where
+        S: Subscriber + for<'span> LookupSpan<'span> + Send + Sync,
+    {
+        let console_logger =
+            json_layer(layer()).with_filter(filter(config.console_filter.as_deref())?);
+
+        let (file_logger, file_guard) = match config.file {
+            Some(file) => {
+                let (writer, guard) = file.writer()?;
+                let file_logger = json_layer(layer().with_writer(writer))
+                    .with_filter(filter(config.console_filter.as_deref())?);
+                (Some(file_logger), Some(guard))
+            }
+            None => (None, None),
+        };
+
+        let log_exporter = LogExporter::builder().with_tonic().build()?;
+
+        let logger_provider = SdkLoggerProvider::builder()
+            .with_batch_exporter(InstrumentedExporter::new(log_exporter, "logs"))
+            .with_resource(resource.clone())
+            .build();
+
+        // Events emitted while exporting are never exported themselves to avoid feedback loops
+        let otel_logger = OpenTelemetryTracingBridge::new(&logger_provider).with_filter(
+            filter(config.otlp_logs_filter.as_deref())?
+                .and(filter_fn(|metadata| !is_suppressed(metadata.target()))),
+        );
+
+        // TODO: eyre::wrap_err?
+        let span_exporter = SpanExporter::builder()
+            .with_tonic()
+            .build()
+            .map_err(|e| eyre!("Failed to build SpanExporter: {:?}", e))?;
+
+        let tracer_provider = TracerProviderBuilder::default()
+            .with_batch_exporter(InstrumentedExporter::new(span_exporter, "traces"))
+            .with_resource(resource.clone())
+            .build();
+
+        let tracer = tracer_provider.tracer("otel-spans");
+        let otel_tracer = OpenTelemetryLayer::new(tracer).with_filter(
+            filter(config.otlp_traces_filter.as_deref())?
+                .and(filter_fn(|metadata| !is_suppressed(metadata.target()))),
+        );
+
+        // Every layer is filtered on its own so that callers can compose theirs freely
+        let layer = otel_logger
+            .and_then(otel_tracer)
+            .and_then(console_logger)
+            .and_then(file_logger)
+            .boxed();
+
+        Ok((
+            Self {
+                tracer_provider,
+                _file_guard: file_guard,
+            },
+            layer,
+        ))
+    }
+
+    /// Sets the global tracer provider and propagator.
+    pub fn set_global(&self) {
+        set_tracer_provider(self.tracer_provider.clone());
+        set_text_map_propagator(TraceContextPropagator::new());
+    }
+}
+
+/// Parses the given directives, or falls back to `RUST_LOG` and then `info` when unset.
+fn filter(directives: Option<&str>) -> Result<EnvFilter> {
+    match directives {
+        Some(directives) => EnvFilter::try_new(directives)
+            .map_err(|e| eyre!("Invalid filter directives {directives:?}: {e}")),
+        None => Ok(EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::new("info"))),
+    }
+}
+
+/// Applies the JSON formatting shared by every local log sink.
+fn json_layer<S, W>(
+    layer: fmt::Layer<S, DefaultFields, Format, W>,
+) -> fmt::Layer<S, JsonFields, Format<Json>, W> {
+    layer
+        .json()
+        .with_current_span(true)
+        .flatten_event(true)
+        .with_target(true)
+        .with_span_list(false)
+}